@@ -0,0 +1,85 @@
+use std::ops::Range;
+use std::thread;
+
+use capstone::Capstone;
+
+use crate::core::{Arch, ALIGNMENT};
+use crate::gadget::GadgetInsn;
+
+/// Instructions of a block of code, decoded once at every aligned offset
+///
+/// An instruction's size is given by its encoding, so each offset holds at
+/// most one decoding. The 2 and 4 bytes candidates preceding offset `off` are
+/// the entries at `off - 2` and `off - 4` whose size matches the distance.
+pub struct InsnCache {
+    arch: Arch,
+    addr: u64,
+    insns: Vec<Option<GadgetInsn>>,
+}
+
+impl InsnCache {
+
+    pub fn build(arch: Arch, code: &[u8], addr: u64) -> Self {
+        return InsnCache {
+            arch,
+            addr,
+            insns: decode_range(&arch.capstone(), code, addr, 0..code.len()),
+        };
+    }
+
+    /// Decodes the code in contiguous chunks, one thread and Capstone handle each
+    pub fn build_parallel(arch: Arch, code: &[u8], addr: u64, threads: usize) -> Self {
+        let chunk = code.len().div_ceil(threads.max(1)).div_ceil(ALIGNMENT) * ALIGNMENT;
+        let chunk = chunk.max(ALIGNMENT);
+        let insns = thread::scope(|s| {
+            let handles: Vec<_> = (0..code.len()).step_by(chunk).map(|start| {
+                let end = (start + chunk).min(code.len());
+                s.spawn(move || decode_range(&arch.capstone(), code, addr, start..end))
+            }).collect();
+            handles.into_iter().flat_map(|h| h.join().expect("Decoding thread panicked")).collect()
+        });
+        return InsnCache { arch, addr, insns };
+    }
+
+    pub fn arch(&self) -> Arch {
+        return self.arch;
+    }
+
+    /// Address of the first byte of the code
+    pub fn addr(&self) -> u64 {
+        return self.addr;
+    }
+
+    /// Size of the decoded code, in bytes
+    pub fn len(&self) -> usize {
+        return self.insns.len() * ALIGNMENT;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.insns.is_empty();
+    }
+
+    /// Instruction starting at offset `off`, if it is aligned and decodes
+    pub fn at(&self, off: u64) -> Option<&GadgetInsn> {
+        if !off.is_multiple_of(ALIGNMENT as u64) {
+            return None;
+        }
+        return self.insns.get((off / ALIGNMENT as u64) as usize)?.as_ref();
+    }
+
+    /// Decoded instructions with their offset
+    pub fn iter(&self) -> impl Iterator<Item=(u64, &GadgetInsn)> {
+        self.insns.iter().enumerate().filter_map(|(i, ins)| {
+            ins.as_ref().map(|ins| ((i * ALIGNMENT) as u64, ins))
+        })
+    }
+
+}
+
+fn decode_range(cs: &Capstone, code: &[u8], addr: u64, range: Range<usize>) -> Vec<Option<GadgetInsn>> {
+    range.step_by(ALIGNMENT).map(|off| {
+        let insns = cs.disasm_count(&code[off..], addr + off as u64, 1).ok()?;
+        let ins = insns.first()?;
+        GadgetInsn::create(cs, ins).ok()
+    }).collect()
+}
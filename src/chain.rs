@@ -0,0 +1,793 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use capstone::RegId;
+use capstone::arch::riscv::{RiscVInsn, RiscVInsn::*, RiscVReg::*};
+
+use crate::core::{self, Arch};
+use crate::emu::{Emulator, Expr};
+use crate::err::RVError;
+use crate::gadget::{Dispatch, Gadget, RootKind, Stride};
+
+/// Address given to the table when emulating the dispatcher
+const TABLE_PROBE: u64 = 0x10000;
+
+/// State a JOP chain must reach: register values, then optionally a system call
+#[derive (Clone, Debug, Default)]
+pub struct Goal {
+    pub regs: Vec<(RegId, u64)>,
+    pub ecall: bool,
+}
+
+/// Parses a goal such as `a0=0x1000,a7=221,ecall`, values may be negative
+pub fn goal_from_str(goal: &str) -> Result<Goal, RVError> {
+    let mut parsed = Goal::default();
+    for item in goal.split(',').map(str::trim) {
+        if parsed.ecall {
+            return Err(RVError {msg: String::from("ecall must end the goal")});
+        }
+        if item == "ecall" {
+            parsed.ecall = true;
+            continue;
+        }
+        let (reg, val) = item.split_once('=').ok_or(RVError {msg: format!("expected <reg>=<val> or ecall, got '{}'", item)})?;
+        let reg = core::reg_from_str(reg)?;
+        if parsed.regs.iter().any(|(r, _)| *r == reg) {
+            return Err(RVError {msg: format!("{} is assigned twice", core::reg_name(reg))});
+        }
+        let val = match val.trim().strip_prefix('-') {
+            Some(abs) => core::int_from_str(abs)?.wrapping_neg(),
+            None => core::int_from_str(val)?,
+        };
+        parsed.regs.push((reg, val));
+    }
+    if parsed.regs.is_empty() && !parsed.ecall {
+        return Err(RVError {msg: String::from("empty goal")});
+    }
+    return Ok(parsed);
+}
+
+/// Initial value a register must hold when the chain starts
+#[derive (Clone, Debug, PartialEq, Eq)]
+pub enum Init {
+    Value(u64),
+    /// Address of the dispatcher gadget, to jump back to it
+    Dispatcher,
+    /// Offset from the address of the first table entry
+    Table(i64),
+}
+
+/// Gadget run from the dispatch table, with what it is used for
+#[derive (Clone, Debug)]
+pub struct Step {
+    pub gadget: Gadget,
+    pub purpose: String,
+}
+
+/// JOP chain: a dispatcher walking a table of functional gadgets
+#[derive (Clone, Debug)]
+pub struct Chain {
+    pub dispatcher: Gadget,
+    pub dispatch: Dispatch,
+    /// Distance between two table entries, in bytes
+    pub stride: i64,
+    /// Size of a table entry, in bytes
+    pub entry_size: u8,
+    pub steps: Vec<Step>,
+    pub regs: BTreeMap<u16, Init>,
+    /// Stack content expected by the gadgets, relative to the initial `sp`
+    pub stack: Vec<(i64, u8, u64)>,
+    /// Address of the first table entry, symbolic when unknown
+    pub table: Option<u64>,
+    pub arch: Arch,
+}
+
+/// What a functional gadget needs and destroys
+#[derive (Clone, Debug)]
+struct Candidate {
+    gadget: Gadget,
+    goal: Option<RegId>,
+    /// Register holding the dispatcher address when the gadget ends
+    back: Option<RegId>,
+    writes: Vec<RegId>,
+    regs: Vec<(RegId, u64)>,
+    stack: Vec<(i64, u8, u64)>,
+    how: String,
+}
+
+fn reg(id: u32) -> RegId {
+    return RegId(id as u16);
+}
+
+/// Whether the gadget only reads memory from the stack, and does not move
+/// `sp` unless `moves_sp`
+fn is_safe(gadget: &Gadget, upto: usize, moves_sp: bool) -> bool {
+    let sp = reg(RISCV_REG_SP);
+    return gadget.insns()[..upto].iter().all(|ins| {
+        !ins.writes_memory()
+            && (moves_sp || !ins.regs_written().contains(&sp))
+            && !core::is_cond_branch(ins.id())
+            && ins.mem_operand().is_none_or(|(base, _)| base == sp)
+    });
+}
+
+/// Value of `expr` narrowed to `size` bytes, if reading it back gives `expr`
+fn narrow(val: u64, size: u8, signed: bool, arch: Arch) -> Option<u64> {
+    if size >= 8 {
+        return Some(val);
+    }
+    let bits = size as u32 * 8;
+    let low = val & ((1u64 << bits) - 1);
+    let back = if signed { ((low << (64 - bits)) as i64 >> (64 - bits)) as u64 } else { low };
+    return (arch.trunc(back) == arch.trunc(val)).then_some(low);
+}
+
+/// Ways the gadget can set `goal` to `val` before jumping back to the dispatcher
+fn functional(gadget: &Gadget, goal: RegId, val: u64, arch: Arch) -> Option<Candidate> {
+    if !is_safe(gadget, gadget.insns().len(), false) {
+        return None;
+    }
+    let effects = Emulator::new(arch).run(gadget).ok()?;
+    if !effects.writes.is_empty() || !effects.notes.is_empty() {
+        return None;
+    }
+    let back = match effects.target.as_ref()? {
+        Expr::Reg(back) => *back,
+        _ => return None,
+    };
+    let result = &effects.regs.iter().find(|(r, _)| *r == goal)?.1;
+    let writes: Vec<RegId> = effects.regs.iter().map(|(r, _)| *r).collect();
+    let val = arch.trunc(val);
+
+    let mut candidate = Candidate {gadget: gadget.clone(), goal: Some(goal), back: Some(back), writes, regs: Vec::new(), stack: Vec::new(), how: String::new()};
+    match result.split_offset() {
+        (Expr::Const(_), _) if result.as_const() == Some(val) => candidate.how = format!("{} = {:#x}", core::reg_name(goal), val),
+        (Expr::Reg(src), off) => {
+            candidate.regs.push((*src, arch.trunc(val.wrapping_sub(off))));
+            candidate.how = format!("{} = {}", core::reg_name(goal), result);
+        }
+        (Expr::Load(addr, size, signed), 0) => {
+            let (Expr::Reg(base), off) = addr.split_offset() else {
+                return None;
+            };
+            if *base != reg(RISCV_REG_SP) {
+                return None;
+            }
+            candidate.stack.push((arch.signed(off), *size, narrow(val, *size, *signed, arch)?));
+            candidate.how = format!("{} = {}", core::reg_name(goal), result);
+        }
+        _ => return None,
+    }
+    if back == goal || candidate.writes.contains(&back) || candidate.regs.iter().any(|(r, _)| *r == back) {
+        return None;
+    }
+    return Some(candidate);
+}
+
+/// Gadget performing a system call, with what it writes before it
+fn syscall(gadget: &Gadget) -> Option<Candidate> {
+    let at = gadget.insns().iter().position(|ins| RiscVInsn::from(ins.id().0) == RISCV_INS_ECALL)?;
+    if !is_safe(gadget, at, false) {
+        return None;
+    }
+    let writes = gadget.insns()[..at].iter().flat_map(|ins| ins.regs_written()).collect();
+    return Some(Candidate {gadget: gadget.clone(), goal: None, back: None, writes, regs: Vec::new(), stack: Vec::new(), how: String::from("ecall")});
+}
+
+/// Entry layout of a dispatcher: offset of the first entry from the table
+/// register value, stride and entry size
+fn layout(dispatcher: &Gadget, dispatch: &Dispatch, arch: Arch) -> Option<(i64, i64, u8)> {
+    let mut emu = Emulator::new(arch);
+    match dispatch.table {
+        Some(table) => {
+            emu.set_reg(table, TABLE_PROBE).ok()?;
+            emu.set_reg(dispatch.reg, 0).ok()?;
+        }
+        None => emu.set_reg(dispatch.reg, TABLE_PROBE).ok()?,
+    }
+    let effects = emu.run(dispatcher).ok()?;
+    let (Expr::Load(addr, size, _), _) = effects.target.as_ref()?.split_offset() else {
+        return None;
+    };
+    let offset = arch.signed(addr.as_const()?.wrapping_sub(TABLE_PROBE));
+    let stride = match dispatch.stride {
+        Stride::Imm(stride) if stride.unsigned_abs() >= *size as u64 => stride,
+        _ => return None,
+    };
+    return Some((offset, stride, *size));
+}
+
+/// Picks one gadget per goal register, extending the goal order one register
+/// at a time. An order whose start leaves no gadget for the next register is
+/// abandoned with every order sharing that start.
+fn assign(candidates: &[Vec<Candidate>], pinned: &[RegId], dispatcher_writes: &[RegId], chosen: &mut Vec<Candidate>, left: &mut Vec<usize>, budget: &mut usize) -> bool {
+    if left.is_empty() {
+        return true;
+    }
+    for i in 0..left.len() {
+        if *budget == 0 {
+            return false;
+        }
+        *budget -= 1;
+        let fits = |c: &Candidate| {
+            let mut all: Vec<&Candidate> = chosen.iter().collect();
+            all.push(c);
+            let needed: Vec<RegId> = all.iter()
+                .flat_map(|c| c.regs.iter().map(|(r, _)| *r).chain(c.back))
+                .chain(pinned.iter().copied())
+                .collect();
+            // Registers read by a gadget must not be written by any other one
+            let clobbers = all.iter().any(|c| c.writes.iter().any(|w| needed.contains(w)))
+                || dispatcher_writes.iter().any(|w| needed.contains(w) || all.iter().any(|c| c.goal == Some(*w)));
+            // Goals already reached must be preserved
+            let overwrites = chosen.iter().any(|done| done.goal.is_some_and(|g| c.writes.contains(&g)));
+            // Conflicting requirements on the same register or stack slot
+            let conflicts = all.iter().flat_map(|c| &c.regs)
+                .any(|(r, v)| all.iter().flat_map(|c| &c.regs).any(|(r2, v2)| r == r2 && v != v2));
+            let stack_conflicts = all.iter().flat_map(|c| &c.stack)
+                .any(|(o, s, v)| all.iter().flat_map(|c| &c.stack).any(|(o2, s2, v2)| o == o2 && (s != s2 || v != v2)));
+            !clobbers && !overwrites && !conflicts && !stack_conflicts
+        };
+        let Some(pick) = candidates[left[i]].iter().find(|c| fits(c)) else {
+            continue;
+        };
+        chosen.push(pick.clone());
+        let goal = left.remove(i);
+        if assign(candidates, pinned, dispatcher_writes, chosen, left, budget) {
+            return true;
+        }
+        left.insert(i, goal);
+        chosen.pop();
+    }
+    return false;
+}
+
+/// Builds a JOP chain reaching `goal` from the gadgets found in the code
+pub fn compile(gadgets: &[Gadget], goal: &Goal, arch: Arch) -> Result<Chain, RVError> {
+    // Functional gadgets, constants first then shorter ones
+    let mut candidates: Vec<Vec<Candidate>> = goal.regs.iter()
+        .map(|(r, v)| gadgets.iter().filter_map(|g| functional(g, *r, *v, arch)).collect())
+        .collect();
+    for list in &mut candidates {
+        list.sort_by_key(|c| (c.regs.len() + c.stack.len(), c.gadget.insns().len()));
+    }
+    if let Some(((reg, _), _)) = goal.regs.iter().zip(&candidates).find(|(_, list)| list.is_empty()) {
+        return Err(RVError {msg: format!("no gadget sets {} and jumps back to a register", core::reg_name(*reg))});
+    }
+    let goals: Vec<RegId> = goal.regs.iter().map(|(r, _)| *r).collect();
+    let call = if goal.ecall {
+        let syscalls: Vec<Candidate> = gadgets.iter().filter_map(syscall).collect();
+        if syscalls.is_empty() {
+            return Err(RVError {msg: String::from("no gadget performs a system call")});
+        }
+        let call = syscalls.into_iter().find(|c| !c.writes.iter().any(|w| goals.contains(w)));
+        Some(call.ok_or(RVError {msg: String::from("no gadget performs a system call without clobbering the goal registers")})?)
+    } else {
+        None
+    };
+
+    // Returns are never dispatchers, see `Gadget::dispatch`. Pointer walks come
+    // first, then shorter gadgets.
+    let sp = reg(RISCV_REG_SP);
+    let mut dispatchers: Vec<(&Gadget, Dispatch)> = gadgets.iter()
+        .filter_map(|g| Some((g, g.dispatch()?)))
+        .filter(|(_, d)| d.reg != sp && d.table != Some(sp))
+        .collect();
+    dispatchers.sort_by_key(|(g, d)| (d.table.is_some(), g.insns().len()));
+    if dispatchers.is_empty() {
+        return Err(RVError {msg: String::from("no dispatcher gadget found")});
+    }
+
+    let mut budget = 100_000;
+    for (dispatcher, dispatch) in dispatchers {
+        let Some((offset, stride, entry_size)) = layout(dispatcher, &dispatch, arch) else {
+            continue;
+        };
+        // The stack slots read by the functional gadgets are relative to an `sp`
+        // that the dispatcher must leave in place
+        let effects = match dispatcher.effects() {
+            Some(effects) if effects.writes.is_empty() && effects.notes.is_empty() => effects,
+            _ => continue,
+        };
+        if effects.regs.iter().any(|(r, _)| *r == sp) {
+            continue;
+        }
+        let dispatcher_writes: Vec<RegId> = effects.regs.iter().map(|(r, _)| *r).filter(|r| *r != dispatch.reg).collect();
+        let pinned: Vec<RegId> = [Some(dispatch.reg), dispatch.table].into_iter().flatten().collect();
+
+        let mut steps = Vec::new();
+        if assign(&candidates, &pinned, &dispatcher_writes, &mut steps, &mut (0..goals.len()).collect(), &mut budget) {
+            steps.extend(call);
+            return Ok(Chain::build(dispatcher.clone(), dispatch, offset, stride, entry_size, steps, arch));
+        }
+    }
+    return Err(RVError {msg: String::from("no dispatcher can run the functional gadgets without them clobbering each other")});
+}
+
+impl Chain {
+
+    fn build(dispatcher: Gadget, dispatch: Dispatch, offset: i64, stride: i64, entry_size: u8, candidates: Vec<Candidate>, arch: Arch) -> Self {
+        let mut regs = BTreeMap::new();
+        match dispatch.table {
+            Some(table) => {
+                regs.insert(table.0, Init::Table(-offset));
+                regs.insert(dispatch.reg.0, Init::Value(0));
+            }
+            None => {
+                regs.insert(dispatch.reg.0, Init::Table(-offset));
+            }
+        }
+        let mut stack = Vec::new();
+        let mut steps = Vec::new();
+        for c in candidates {
+            if let Some(back) = c.back {
+                regs.insert(back.0, Init::Dispatcher);
+            }
+            for (r, v) in &c.regs {
+                regs.insert(r.0, Init::Value(*v));
+            }
+            stack.extend(c.stack.iter().copied());
+            steps.push(Step {gadget: c.gadget, purpose: c.how});
+        }
+        stack.sort();
+        stack.dedup();
+        return Chain {dispatcher, dispatch, stride, entry_size, steps, regs, stack, table: None, arch};
+    }
+
+    /// Address of the i-th table entry, relative to the first one
+    pub fn entry_offset(&self, i: usize) -> i64 {
+        return self.stride * i as i64;
+    }
+
+    /// Dispatch table content, from the entry with the lowest address
+    pub fn table_bytes(&self) -> Vec<u8> {
+        let entries = self.steps.len() as i64;
+        let low = self.entry_offset(0).min(self.entry_offset(self.steps.len().saturating_sub(1)));
+        let mut bytes = vec![0u8; (self.stride.abs() * (entries - 1)) as usize + self.entry_size as usize];
+        for (i, step) in self.steps.iter().enumerate() {
+            let at = (self.entry_offset(i) - low) as usize;
+            let addr = self.arch.encode_addr(step.gadget.address());
+            bytes[at..at + self.entry_size as usize].copy_from_slice(&addr[..self.entry_size as usize]);
+        }
+        return bytes;
+    }
+
+    /// `base+off`, with the table address resolved when it is known
+    fn table_rel(&self, off: i64) -> String {
+        return match self.table {
+            Some(table) => format!("{:#x}", self.arch.trunc(table.wrapping_add(off as u64))),
+            None => rel("table", off),
+        };
+    }
+
+}
+
+fn rel(base: &str, off: i64) -> String {
+    return match off {
+        0 => String::from(base),
+        off if off < 0 => format!("{}-{:#x}", base, off.unsigned_abs()),
+        off => format!("{}+{:#x}", base, off),
+    };
+}
+
+fn inline(gadget: &Gadget) -> String {
+    return gadget.insns().iter().chain(std::iter::once(gadget.root()))
+        .map(|ins| ins.to_string().trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join(" ; ");
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = self.arch.addr_width() + 2;
+        writeln!(f, "Dispatcher:")?;
+        writeln!(f, "  {:#0w$x}   {}   ({})", self.dispatcher.address(), inline(&self.dispatcher), self.dispatch, w = w)?;
+        writeln!(f, "Initial registers:")?;
+        for (id, init) in &self.regs {
+            let value = match init {
+                Init::Value(val) => format!("{:#x}", val),
+                Init::Dispatcher => format!("{:#x} (dispatcher)", self.dispatcher.address()),
+                Init::Table(off) => self.table_rel(*off),
+            };
+            writeln!(f, "  {:<4} = {}", core::reg_name(RegId(*id)), value)?;
+        }
+        if !self.stack.is_empty() {
+            writeln!(f, "Initial stack:")?;
+            for (off, size, val) in &self.stack {
+                writeln!(f, "  [sp{:+}] = {:#x} ({} bytes)", off, val, size)?;
+            }
+        }
+        writeln!(f, "Dispatch table ({} byte entries, stride {:+}):", self.entry_size, self.stride)?;
+        for (i, step) in self.steps.iter().enumerate() {
+            let sym = step.gadget.symbol().map(|s| format!(" <{}>", s)).unwrap_or_default();
+            writeln!(f, "  {:<c$} {:#0w$x}{}   {}   ({})", self.table_rel(self.entry_offset(i)), step.gadget.address(), sym, inline(&step.gadget), step.purpose, c = w.max(12), w = w)?;
+        }
+        let hex: String = self.table_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(f, "Table bytes: {}", hex)?;
+        Ok(())
+    }
+}
+
+/// Output format of a ROP payload
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadFormat {
+    Text,
+    Hex,
+    Bytes,
+    Python,
+}
+
+pub fn payload_format_from_str(format: &str) -> Result<PayloadFormat, RVError> {
+    match format {
+        "text" => Ok(PayloadFormat::Text),
+        "hex" => Ok(PayloadFormat::Hex),
+        "bytes" => Ok(PayloadFormat::Bytes),
+        "python" => Ok(PayloadFormat::Python),
+        _ => Err(RVError {msg: String::from("not a payload format (text, hex, bytes, python)")}),
+    }
+}
+
+/// Value placed on the stack by a ROP chain
+#[derive (Clone, Debug)]
+pub struct Slot {
+    /// Offset from `sp` when the first gadget starts
+    pub off: i64,
+    pub size: u8,
+    pub value: u64,
+    pub purpose: String,
+}
+
+/// ROP chain: gadgets returning to each other through addresses read from the stack
+#[derive (Clone, Debug)]
+pub struct RopChain {
+    /// Address of the first gadget, to return to
+    pub entry: u64,
+    pub steps: Vec<Step>,
+    pub slots: Vec<Slot>,
+    /// Stack content from `sp` when the first gadget starts
+    pub payload: Vec<u8>,
+    pub arch: Arch,
+}
+
+/// Stack layout of a `ret` terminated gadget
+#[derive (Clone, Debug)]
+struct Frame {
+    gadget: Gadget,
+    sets: Vec<RegId>,
+    writes: Vec<RegId>,
+    /// Goal values loaded from the stack: offset, size, value, register
+    slots: Vec<(i64, u8, u64, RegId)>,
+    /// Where the return address is loaded from: offset and size
+    ret: (i64, u8, bool),
+    /// Amount `sp` is moved up by
+    delta: i64,
+    how: Vec<String>,
+}
+
+/// Offset from the initial `sp` of a stack address
+fn stack_offset(addr: &Expr, arch: Arch) -> Option<i64> {
+    match addr.split_offset() {
+        (Expr::Reg(base), off) if *base == reg(RISCV_REG_SP) => Some(arch.signed(off)),
+        _ => None,
+    }
+}
+
+/// Goal registers a `ret` terminated gadget sets from constants or the stack
+fn frame(gadget: &Gadget, goal: &Goal, arch: Arch) -> Option<Frame> {
+    if !is_safe(gadget, gadget.insns().len(), true) {
+        return None;
+    }
+    let effects = Emulator::new(arch).run(gadget).ok()?;
+    if !effects.writes.is_empty() || !effects.notes.is_empty() {
+        return None;
+    }
+    let sp = reg(RISCV_REG_SP);
+    let delta = match effects.regs.iter().find(|(r, _)| *r == sp) {
+        Some((_, val)) => stack_offset(val, arch)?,
+        None => 0,
+    };
+    let ret = match effects.target.as_ref()?.split_offset() {
+        (Expr::Load(addr, size, signed), 0) => (stack_offset(addr, arch)?, *size, *signed),
+        _ => return None,
+    };
+    if delta < 0 || ret.0 < 0 {
+        return None;
+    }
+
+    let mut frame = Frame {gadget: gadget.clone(), sets: Vec::new(), writes: effects.regs.iter().map(|(r, _)| *r).collect(), slots: Vec::new(), ret, delta, how: Vec::new()};
+    for (goal, val) in &goal.regs {
+        let Some((_, result)) = effects.regs.iter().find(|(r, _)| r == goal) else {
+            continue;
+        };
+        let val = arch.trunc(*val);
+        match result {
+            Expr::Const(c) if *c == val => {}
+            Expr::Load(addr, size, signed) => {
+                let off = stack_offset(addr, arch)?;
+                if off < 0 {
+                    continue;
+                }
+                let Some(narrowed) = narrow(val, *size, *signed, arch) else {
+                    continue;
+                };
+                frame.slots.push((off, *size, narrowed, *goal));
+            }
+            _ => continue,
+        }
+        frame.sets.push(*goal);
+        frame.how.push(format!("{} = {}", core::reg_name(*goal), result));
+    }
+    return Some(frame);
+}
+
+/// Writes `size` bytes of `value` at `off`, failing on a conflicting byte
+fn place(stack: &mut BTreeMap<i64, u8>, off: i64, size: u8, value: u64) -> bool {
+    for (i, byte) in value.to_le_bytes()[..size as usize].iter().enumerate() {
+        if *stack.entry(off + i as i64).or_insert(*byte) != *byte {
+            return false;
+        }
+    }
+    return true;
+}
+
+/// Lays out the frames on the stack, the last one returning to `last`
+fn lay_out(frames: &[&Frame], last: Option<&Candidate>, arch: Arch) -> Option<RopChain> {
+    let mut stack = BTreeMap::new();
+    let mut slots = Vec::new();
+    let mut steps = Vec::new();
+    let mut sp = 0;
+
+    for (i, frame) in frames.iter().enumerate() {
+        for (off, size, value, goal) in &frame.slots {
+            if !place(&mut stack, sp + off, *size, *value) {
+                return None;
+            }
+            slots.push(Slot {off: sp + off, size: *size, value: *value, purpose: core::reg_name(*goal)});
+        }
+        let next = frames.get(i + 1).map(|f| &f.gadget).or(last.map(|c| &c.gadget));
+        if let Some(next) = next {
+            let (off, size, signed) = frame.ret;
+            let value = narrow(next.address(), size, signed, arch)?;
+            if !place(&mut stack, sp + off, size, value) {
+                return None;
+            }
+            slots.push(Slot {off: sp + off, size, value, purpose: format!("return to {:#x}", next.address())});
+        }
+        steps.push(Step {gadget: frame.gadget.clone(), purpose: frame.how.join(", ")});
+        sp += frame.delta;
+    }
+    if let Some(call) = last {
+        steps.push(Step {gadget: call.gadget.clone(), purpose: call.how.clone()});
+    }
+
+    let len = stack.keys().next_back().map_or(0, |end| end + 1).max(sp) as usize;
+    let mut payload = vec![0u8; len];
+    for (off, byte) in stack {
+        payload[off as usize] = byte;
+    }
+    slots.sort_by_key(|slot| slot.off);
+    return Some(RopChain {entry: steps[0].gadget.address(), steps, slots, payload, arch});
+}
+
+/// Frames setting every goal register without clobbering those already set
+fn search(frames: &[Frame], goals: &[RegId], done: &mut Vec<RegId>, chosen: &mut Vec<usize>, budget: &mut usize, found: &mut dyn FnMut(&[usize]) -> bool) -> bool {
+    if goals.iter().all(|g| done.contains(g)) {
+        return found(chosen);
+    }
+    if chosen.len() >= goals.len() {
+        return false;
+    }
+    for (i, frame) in frames.iter().enumerate() {
+        if *budget == 0 {
+            return false;
+        }
+        *budget -= 1;
+        let progress = frame.sets.iter().any(|r| !done.contains(r));
+        let clobbers = frame.writes.iter().any(|w| done.contains(w) && !frame.sets.contains(w));
+        if !progress || clobbers {
+            continue;
+        }
+        let before = done.len();
+        done.extend(frame.sets.iter().filter(|r| !done.contains(r)).copied().collect::<Vec<_>>());
+        chosen.push(i);
+        if search(frames, goals, done, chosen, budget, found) {
+            return true;
+        }
+        chosen.pop();
+        done.truncate(before);
+    }
+    return false;
+}
+
+/// Builds a ROP chain setting the goal registers from the stack, then
+/// reaching a system call
+pub fn compile_rop(gadgets: &[Gadget], goal: &Goal, arch: Arch) -> Result<RopChain, RVError> {
+    let (sp, ra) = (reg(RISCV_REG_SP), reg(RISCV_REG_RA));
+    if goal.regs.iter().any(|(r, _)| *r == sp || *r == ra) {
+        return Err(RVError {msg: String::from("sp and ra are used by the chain and cannot be part of the goal")});
+    }
+
+    let mut frames: Vec<Frame> = gadgets.iter()
+        .filter(|g| g.kind() == RootKind::Return)
+        .filter_map(|g| frame(g, goal, arch))
+        .filter(|f| !f.sets.is_empty())
+        .collect();
+    frames.sort_by_key(|f| (usize::MAX - f.sets.len(), f.gadget.insns().len(), f.delta));
+    if let Some((missing, _)) = goal.regs.iter().find(|(r, _)| !frames.iter().any(|f| f.sets.contains(r))) {
+        return Err(RVError {msg: format!("no ret gadget sets {} from the stack", core::reg_name(*missing))});
+    }
+
+    let goals: Vec<RegId> = goal.regs.iter().map(|(r, _)| *r).collect();
+    let mut syscalls: Vec<Candidate> = Vec::new();
+    if goal.ecall {
+        syscalls = gadgets.iter().filter_map(syscall).filter(|c| !c.writes.iter().any(|w| goals.contains(w))).collect();
+        syscalls.sort_by_key(|c| c.gadget.insns().len());
+        if syscalls.is_empty() {
+            return Err(RVError {msg: String::from("no gadget performs a system call without clobbering the goal registers")});
+        }
+    }
+    let last = syscalls.first();
+    if goals.is_empty() {
+        let call = last.ok_or(RVError {msg: String::from("empty goal")})?;
+        return Ok(lay_out(&[], Some(call), arch).expect("a lone gadget always fits on the stack"));
+    }
+
+    let mut chain = None;
+    let mut budget = 100_000;
+    search(&frames, &goals, &mut Vec::new(), &mut Vec::new(), &mut budget, &mut |chosen| {
+        let picked: Vec<&Frame> = chosen.iter().map(|i| &frames[*i]).collect();
+        chain = lay_out(&picked, last, arch);
+        chain.is_some()
+    });
+    return chain.ok_or(RVError {msg: String::from("no ret gadgets can set the goal registers without clobbering each other")});
+}
+
+impl RopChain {
+
+    pub fn hex(&self) -> String {
+        return self.payload.iter().map(|b| format!("{:02x}", b)).collect();
+    }
+
+    /// Python snippet defining `entry` and `payload`
+    pub fn python(&self) -> String {
+        let mut out = String::new();
+        for step in &self.steps {
+            out += &format!("# {:#x}: {}   ({})\n", step.gadget.address(), inline(&step.gadget), step.purpose);
+        }
+        out += &format!("entry = {:#x}\n", self.entry);
+        out += &format!("payload = bytes.fromhex('{}')\n", self.hex());
+        return out;
+    }
+
+}
+
+impl fmt::Display for RopChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = self.arch.addr_width() + 2;
+        writeln!(f, "Entry: {:#x} (return address to overwrite)", self.entry)?;
+        writeln!(f, "Gadgets:")?;
+        for step in &self.steps {
+            let sym = step.gadget.symbol().map(|s| format!(" <{}>", s)).unwrap_or_default();
+            writeln!(f, "  {:#0w$x}{}   {}   ({})", step.gadget.address(), sym, inline(&step.gadget), step.purpose, w = w)?;
+        }
+        writeln!(f, "Stack ({} bytes from sp at entry):", self.payload.len())?;
+        for slot in &self.slots {
+            writeln!(f, "  [sp+{:#x}] = {:#x} ({} bytes, {})", slot.off, slot.value, slot.size, slot.purpose)?;
+        }
+        writeln!(f, "Payload: {}", self.hex())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Scanner;
+
+    /// Two `ret` epilogues, `ecall; ret`, the `ld a5, 0(s0); addi s0, s0, 8;
+    /// jr a5` dispatcher then functional gadgets jumping back through `s3`
+    const CODE: [u8; 50] = [
+        0x02, 0x65, 0xa2, 0x65, 0xe2, 0x60, 0x05, 0x61, 0x82, 0x80, 0xa2, 0x68,
+        0xc2, 0x60, 0x05, 0x61, 0x82, 0x80, 0x73, 0x00, 0x00, 0x00, 0x82, 0x80,
+        0x1c, 0x60, 0x21, 0x04, 0x82, 0x87, 0x52, 0x85, 0x82, 0x89, 0xc2, 0x65,
+        0x82, 0x89, 0x93, 0x08, 0xd0, 0x0d, 0x82, 0x89, 0x73, 0x00, 0x00, 0x00,
+        0x82, 0x89,
+    ];
+    const BASE: u64 = 0x8000;
+
+    #[test]
+    fn dispatch_table_layout() {
+        let gadgets: Vec<Gadget> = Scanner::new(&CODE).base(BASE).arch(Arch::RV64).scan().collect();
+        let chain = compile(&gadgets, &goal_from_str("a0=0x1234,a1=0x99,a7=221,ecall").unwrap(), Arch::RV64).unwrap();
+
+        assert_eq!(chain.dispatcher.address(), BASE + 0x18);
+        assert_eq!((chain.dispatch.reg, chain.stride, chain.entry_size), (reg(RISCV_REG_S0), 8, 8));
+        let steps: Vec<u64> = chain.steps.iter().map(|s| s.gadget.address() - BASE).collect();
+        assert_eq!(steps, [0x1e, 0x22, 0x26, 0x12]);
+        assert_eq!(chain.regs.get(&(RISCV_REG_S0 as u16)), Some(&Init::Table(0)));
+        assert_eq!(chain.regs.get(&(RISCV_REG_S3 as u16)), Some(&Init::Dispatcher));
+        assert_eq!(chain.regs.get(&(RISCV_REG_S4 as u16)), Some(&Init::Value(0x1234)));
+        assert_eq!(chain.stack, [(16, 8, 0x99)]);
+
+        let mut table = Vec::new();
+        for addr in [0x801e, 0x8022, 0x8026, 0x8012u64] {
+            table.extend(addr.to_le_bytes());
+        }
+        assert_eq!(chain.table_bytes(), table);
+    }
+
+    #[test]
+    fn every_argument_and_syscall_number() {
+        // ld t1, 0(s0); addi s0, s0, 8; jr t1, then li a0..a4, mv a5, s4 and li a7,
+        // each followed by jr s3
+        let code = [
+            0x03, 0x33, 0x04, 0x00, 0x21, 0x04, 0x02, 0x83, 0x05, 0x45, 0x82, 0x89,
+            0x89, 0x45, 0x82, 0x89, 0x0d, 0x46, 0x82, 0x89, 0x91, 0x46, 0x82, 0x89,
+            0x15, 0x47, 0x82, 0x89, 0xd2, 0x87, 0x82, 0x89, 0x99, 0x48, 0x82, 0x89,
+        ];
+        let gadgets: Vec<Gadget> = Scanner::new(&code).arch(Arch::RV64).scan().collect();
+        let chain = compile(&gadgets, &goal_from_str("a0=1,a1=2,a2=3,a3=4,a4=5,a5=0x77,a7=6").unwrap(), Arch::RV64).unwrap();
+        assert_eq!(chain.steps.len(), 7);
+        assert_eq!(chain.regs.get(&(RISCV_REG_S4 as u16)), Some(&Init::Value(0x77)));
+    }
+
+    #[test]
+    fn rop_payload_layout() {
+        let gadgets: Vec<Gadget> = Scanner::new(&CODE).base(BASE).arch(Arch::RV64).scan().collect();
+        let chain = compile_rop(&gadgets, &goal_from_str("a0=0x1234,a1=0x99,a7=221,ecall").unwrap(), Arch::RV64).unwrap();
+
+        assert_eq!(chain.entry, BASE);
+        let steps: Vec<u64> = chain.steps.iter().map(|s| s.gadget.address() - BASE).collect();
+        assert_eq!(steps, [0, 0xa, 0x12]);
+        let slots: Vec<(i64, u8, u64, &str)> = chain.slots.iter().map(|s| (s.off, s.size, s.value, s.purpose.as_str())).collect();
+        assert_eq!(slots, [
+            (0, 8, 0x1234, "a0"),
+            (8, 8, 0x99, "a1"),
+            (0x18, 8, 0x800a, "return to 0x800a"),
+            (0x28, 8, 0xdd, "a7"),
+            (0x30, 8, 0x8012, "return to 0x8012"),
+        ]);
+
+        let mut payload = vec![0u8; 64];
+        for (off, value) in [(0, 0x1234u64), (8, 0x99), (0x18, 0x800a), (0x28, 0xdd), (0x30, 0x8012)] {
+            payload[off..off + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(chain.payload, payload);
+
+        // The payload covers the frame the last gadget pops, not only its slots
+        let chain = compile_rop(&gadgets, &goal_from_str("a1=0x99").unwrap(), Arch::RV64).unwrap();
+        assert_eq!(chain.slots.len(), 1);
+        assert_eq!(chain.payload.len(), 32);
+    }
+
+    #[test]
+    fn rop_lone_system_call() {
+        let gadgets: Vec<Gadget> = Scanner::new(&CODE).base(BASE).arch(Arch::RV64).scan().collect();
+        let chain = compile_rop(&gadgets, &goal_from_str("ecall").unwrap(), Arch::RV64).unwrap();
+        assert_eq!(chain.entry, BASE + 0x12);
+        assert_eq!(chain.steps.len(), 1);
+        assert!(chain.slots.is_empty() && chain.payload.is_empty());
+    }
+
+    #[test]
+    fn rop_unreachable_goal() {
+        let gadgets: Vec<Gadget> = Scanner::new(&CODE).base(BASE).arch(Arch::RV64).scan().collect();
+        let Err(err) = compile_rop(&gadgets, &goal_from_str("a0=1,a2=2,ecall").unwrap(), Arch::RV64) else {
+            panic!("no gadget loads a2");
+        };
+        assert_eq!(err.msg, "no ret gadget sets a2 from the stack");
+    }
+
+    #[test]
+    fn rop_payload_formats() {
+        let gadgets: Vec<Gadget> = Scanner::new(&CODE).base(BASE).arch(Arch::RV64).scan().collect();
+        let chain = compile_rop(&gadgets, &goal_from_str("a1=0x99").unwrap(), Arch::RV64).unwrap();
+        let hex = "0000000000000000990000000000000000000000000000000000000000000000";
+        assert_eq!(chain.hex(), hex);
+        assert_eq!(chain.python(), format!(
+            "# 0x8000: ldsp a0, 0(sp) ; ldsp a1, 8(sp) ; ldsp ra, 0x18(sp) ; addi16sp sp, 0x20 ; jr ra   (a1 = i64[sp+0x8])\n\
+             entry = 0x8000\n\
+             payload = bytes.fromhex('{}')\n", hex));
+    }
+
+}
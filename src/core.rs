@@ -1,18 +1,85 @@
 use std::error::Error;
 
+use std::iter;
+
 use capstone::Capstone;
-use capstone::arch::riscv::RiscVOperand;
-use capstone::arch::riscv::{RiscVInsn, RiscVInsn::*, RiscVReg::*};
-use capstone::prelude::{RegId, InsnId};
+use capstone::arch;
+use capstone::arch::riscv::{RiscVInsn, RiscVInsn::*, RiscVReg, RiscVReg::*};
+use capstone::prelude::{RegId, InsnId, BuildsCapstone, BuildsCapstoneExtraMode};
 use elf::{ElfBytes ,endian};
 
-use crate::gadget::{Gadget, GadgetInsn, GadgetRoot};
+use crate::cache::InsnCache;
+use crate::gadget::{Gadget, GadgetInsn, GadgetRoot, KindFilter, RootKind};
 use crate::err::RVError;
 
-const ALIGNMENT: usize = 2;
+pub const ALIGNMENT: usize = 2;
 const MAX_INSSZ: usize = 4;
 const MIN_INSSZ: usize = 2;
 
+/// Base ISA of the analyzed code
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arch {
+    RV32,
+    RV64,
+}
+
+impl Arch {
+
+    pub fn from_class(class: elf::file::Class) -> Self {
+        match class {
+            elf::file::Class::ELF32 => Arch::RV32,
+            elf::file::Class::ELF64 => Arch::RV64,
+        }
+    }
+
+    /// Number of hex digits needed to print an address
+    pub fn addr_width(&self) -> usize {
+        match self {
+            Arch::RV32 => 8,
+            Arch::RV64 => 16,
+        }
+    }
+
+    /// Signed value of a register sized constant
+    pub fn signed(&self, val: u64) -> i64 {
+        match self {
+            Arch::RV32 => val as u32 as i32 as i64,
+            Arch::RV64 => val as i64,
+        }
+    }
+
+    /// Value wrapped to the register width
+    pub fn trunc(&self, val: u64) -> u64 {
+        match self {
+            Arch::RV32 => val & 0xffff_ffff,
+            Arch::RV64 => val,
+        }
+    }
+
+    /// Address as laid out in memory, little-endian on a native word
+    pub fn encode_addr(&self, addr: u64) -> Vec<u8> {
+        match self {
+            Arch::RV32 => (addr as u32).to_le_bytes().to_vec(),
+            Arch::RV64 => addr.to_le_bytes().to_vec(),
+        }
+    }
+
+    pub fn capstone(&self) -> Capstone {
+        let mode = match self {
+            Arch::RV32 => arch::riscv::ArchMode::RiscV32,
+            Arch::RV64 => arch::riscv::ArchMode::RiscV64,
+        };
+        Capstone::new()
+            .riscv()
+            .mode(mode)
+            .extra_mode(iter::once(arch::riscv::ArchExtraMode::RiscVC))
+            .detail(true)
+            .build()
+            .expect("Failed to create Capstone object")
+    }
+
+}
+
 const BRANCH_INSNS: &[RiscVInsn] = &[
     RISCV_INS_JAL,
     RISCV_INS_JALR,
@@ -26,7 +93,6 @@ const BRANCH_INSNS: &[RiscVInsn] = &[
 ];
 
 const ARITHM_INSNS: &[RiscVInsn] = &[
-    RISCV_INS_ADDI,
     RISCV_INS_ADDI,
     RISCV_INS_ADDIW,
     RISCV_INS_C_ADDI16SP,
@@ -36,11 +102,80 @@ const ARITHM_INSNS: &[RiscVInsn] = &[
 ];
 
 const LOAD_INSNS: &[RiscVInsn] = &[
-    RISCV_INS_LW,
+    RISCV_INS_LB,
+    RISCV_INS_LBU,
     RISCV_INS_LH,
+    RISCV_INS_LHU,
+    RISCV_INS_LW,
+    RISCV_INS_LWU,
     RISCV_INS_LD,
+    RISCV_INS_FLW,
+    RISCV_INS_FLD,
     RISCV_INS_C_LW,
     RISCV_INS_C_LD,
+    RISCV_INS_C_LWSP,
+    RISCV_INS_C_LDSP,
+    RISCV_INS_C_FLW,
+    RISCV_INS_C_FLD,
+    RISCV_INS_C_FLWSP,
+    RISCV_INS_C_FLDSP,
+];
+
+const STORE_INSNS: &[RiscVInsn] = &[
+    RISCV_INS_SB,
+    RISCV_INS_SH,
+    RISCV_INS_SW,
+    RISCV_INS_SD,
+    RISCV_INS_FSW,
+    RISCV_INS_FSD,
+    RISCV_INS_C_SW,
+    RISCV_INS_C_SD,
+    RISCV_INS_C_SWSP,
+    RISCV_INS_C_SDSP,
+    RISCV_INS_C_FSW,
+    RISCV_INS_C_FSD,
+    RISCV_INS_C_FSWSP,
+    RISCV_INS_C_FSDSP,
+];
+
+const COND_BRANCH_INSNS: &[RiscVInsn] = &[
+    RISCV_INS_BEQ,
+    RISCV_INS_BNE,
+    RISCV_INS_BLT,
+    RISCV_INS_BGE,
+    RISCV_INS_BLTU,
+    RISCV_INS_BGEU,
+    RISCV_INS_C_BEQZ,
+    RISCV_INS_C_BNEZ,
+];
+
+/// Compressed instructions whose first operand is both source and destination
+const RMW_INSNS: &[RiscVInsn] = &[
+    RISCV_INS_C_ADD,
+    RISCV_INS_C_ADDI,
+    RISCV_INS_C_ADDI16SP,
+    RISCV_INS_C_ADDIW,
+    RISCV_INS_C_ADDW,
+    RISCV_INS_C_AND,
+    RISCV_INS_C_ANDI,
+    RISCV_INS_C_OR,
+    RISCV_INS_C_SLLI,
+    RISCV_INS_C_SRAI,
+    RISCV_INS_C_SRLI,
+    RISCV_INS_C_SUB,
+    RISCV_INS_C_SUBW,
+    RISCV_INS_C_XOR,
+];
+
+/// Registers read by a Linux `ecall` (syscall number and arguments)
+pub const ECALL_READS: &[RiscVReg::Type] = &[
+    RISCV_REG_A7,
+    RISCV_REG_A0,
+    RISCV_REG_A1,
+    RISCV_REG_A2,
+    RISCV_REG_A3,
+    RISCV_REG_A4,
+    RISCV_REG_A5,
 ];
 
 pub fn is_branching(id: InsnId) -> bool {
@@ -55,12 +190,33 @@ pub fn is_load(id: InsnId) -> bool {
     return LOAD_INSNS.contains(&RiscVInsn::from(id.0));
 }
 
+pub fn is_store(id: InsnId) -> bool {
+    return STORE_INSNS.contains(&RiscVInsn::from(id.0));
+}
+
+pub fn is_cond_branch(id: InsnId) -> bool {
+    return COND_BRANCH_INSNS.contains(&RiscVInsn::from(id.0));
+}
+
+pub fn is_rmw(id: InsnId) -> bool {
+    return RMW_INSNS.contains(&RiscVInsn::from(id.0));
+}
+
 pub fn ins_from_str(ins: &str) -> Result<InsnId, RVError> {
     let val = match ins {
         "add" => RISCV_INS_ADD,
         "addi" => RISCV_INS_ADDI,
         "addiw" => RISCV_INS_ADDIW,
         "addw" => RISCV_INS_ADDW,
+        "amoadd.d" => RISCV_INS_AMOADD_D,
+        "amoand.d" => RISCV_INS_AMOAND_D,
+        "amomax.d" => RISCV_INS_AMOMAX_D,
+        "amomaxu.d" => RISCV_INS_AMOMAXU_D,
+        "amomin.d" => RISCV_INS_AMOMIN_D,
+        "amominu.d" => RISCV_INS_AMOMINU_D,
+        "amoor.d" => RISCV_INS_AMOOR_D,
+        "amoswap.d" => RISCV_INS_AMOSWAP_D,
+        "amoxor.d" => RISCV_INS_AMOXOR_D,
         "and" => RISCV_INS_AND,
         "andi" => RISCV_INS_ANDI,
         "auipc" => RISCV_INS_AUIPC,
@@ -120,8 +276,18 @@ pub fn ins_from_str(ins: &str) -> Result<InsnId, RVError> {
         "ebreak" => RISCV_INS_EBREAK,
         "ecall" => RISCV_INS_ECALL,
         "fence" => RISCV_INS_FENCE,
+        "fcvt.d.l" => RISCV_INS_FCVT_D_L,
+        "fcvt.d.lu" => RISCV_INS_FCVT_D_LU,
+        "fcvt.l.d" => RISCV_INS_FCVT_L_D,
+        "fcvt.l.s" => RISCV_INS_FCVT_L_S,
+        "fcvt.lu.d" => RISCV_INS_FCVT_LU_D,
+        "fcvt.lu.s" => RISCV_INS_FCVT_LU_S,
+        "fcvt.s.l" => RISCV_INS_FCVT_S_L,
+        "fcvt.s.lu" => RISCV_INS_FCVT_S_LU,
         "fld" => RISCV_INS_FLD,
         "flw" => RISCV_INS_FLW,
+        "fmv.d.x" => RISCV_INS_FMV_D_X,
+        "fmv.x.d" => RISCV_INS_FMV_X_D,
         "fsd" => RISCV_INS_FSD,
         "fsw" => RISCV_INS_FSW,
         "jal" => RISCV_INS_JAL,
@@ -131,6 +297,7 @@ pub fn ins_from_str(ins: &str) -> Result<InsnId, RVError> {
         "ld" => RISCV_INS_LD,
         "lh" => RISCV_INS_LH,
         "lhu" => RISCV_INS_LHU,
+        "lr.d" => RISCV_INS_LR_D,
         "lui" => RISCV_INS_LUI,
         "lw" => RISCV_INS_LW,
         "lwu" => RISCV_INS_LWU,
@@ -147,6 +314,7 @@ pub fn ins_from_str(ins: &str) -> Result<InsnId, RVError> {
         "remuw" => RISCV_INS_REMUW,
         "remw" => RISCV_INS_REMW,
         "sb" => RISCV_INS_SB,
+        "sc.d" => RISCV_INS_SC_D,
         "sd" => RISCV_INS_SD,
         "sh" => RISCV_INS_SH,
         "sll" => RISCV_INS_SLL,
@@ -251,6 +419,7 @@ pub fn reg_from_str(reg: &str) -> Result<RegId, RVError> {
         "x29" => RISCV_REG_X29,
         "x30" => RISCV_REG_X30,
         "x31" => RISCV_REG_X31,
+        "zero" => RISCV_REG_ZERO,
         "" => RISCV_REG_ZERO,
         _ => RISCV_REG_INVALID,
     };
@@ -260,86 +429,241 @@ pub fn reg_from_str(reg: &str) -> Result<RegId, RVError> {
     }
 }
 
-pub fn get_code<'a>(elf: &'a ElfBytes<endian::AnyEndian>) -> Result<(usize, usize, u64), Box<dyn Error>> {
+/// Parses a comma-separated list of registers and register ranges (`a0,s0-s11`)
+pub fn regs_from_str(regs: &str) -> Result<Vec<RegId>, RVError> {
+    let split = |reg: &str| {
+        let i = reg.find(|c: char| c.is_ascii_digit())?;
+        Some((reg[..i].to_string(), reg[i..].parse::<u32>().ok()?))
+    };
+    let mut list = Vec::new();
+    for item in regs.split(',') {
+        match item.split_once('-') {
+            Some((from, to)) => match (split(from), split(to)) {
+                (Some((prefix, first)), Some((other, last))) if prefix == other && first <= last => {
+                    for n in first..=last {
+                        list.push(reg_from_str(&format!("{}{}", prefix, n))?);
+                    }
+                }
+                _ => return Err(RVError {msg: format!("'{}' is not a register range", item)}),
+            },
+            None => list.push(reg_from_str(item)?),
+        }
+    }
+    return Ok(list);
+}
+
+/// ABI name of an integer or floating point register
+pub fn reg_name(reg: RegId) -> String {
+    const GPRS: [&str; 32] = [
+        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+        "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+        "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+        "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+    ];
+    let id = reg.0 as u32;
+    if (RISCV_REG_X0..=RISCV_REG_X31).contains(&id) {
+        return String::from(GPRS[(id - RISCV_REG_X0) as usize]);
+    }
+    if (RISCV_REG_F0_32..=RISCV_REG_F31_64).contains(&id) {
+        return format!("f{}", (id - RISCV_REG_F0_32) / 2);
+    }
+    return String::from("?");
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal integer
+pub fn int_from_str(val: &str) -> Result<u64, RVError> {
+    let parsed = match val.strip_prefix("0x").or_else(|| val.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => val.parse::<u64>(),
+    };
+    parsed.map_err(|_| RVError {msg: String::from("not an integer")})
+}
+
+/// Parses a string of hexadecimal bytes (`deadbeef`)
+pub fn hex_decode(hex: &str) -> Result<Vec<u8>, RVError> {
+    let err = || RVError {msg: format!("'{}' is not a string of hex bytes", hex)};
+    if !hex.len().is_multiple_of(2) {
+        return Err(err());
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()).ok_or_else(err))
+        .collect()
+}
+
+pub fn arch_from_str(arch: &str) -> Result<Arch, RVError> {
+    match arch {
+        "rv32" => Ok(Arch::RV32),
+        "rv64" => Ok(Arch::RV64),
+        _ => Err(RVError {msg: String::from("not a supported architecture (rv32, rv64)")}),
+    }
+}
+
+/// Executable part of the input, located at `off` in the file and mapped at `addr`
+#[derive (Clone, Copy, Debug)]
+pub struct CodeRegion {
+    pub off: usize,
+    pub size: usize,
+    pub addr: u64,
+}
+
+pub fn get_code_regions(elf: &ElfBytes<endian::AnyEndian>, len: usize) -> Result<Vec<CodeRegion>, Box<dyn Error>> {
+    let mut regions = Vec::new();
+
     if let Some(segs) = elf.segments() {
         for phdr in segs {
-            if phdr.p_flags == elf::abi::PF_R | elf::abi::PF_X {
-                return Ok((phdr.p_offset as usize, phdr.p_filesz as usize, phdr.p_vaddr));
+            if phdr.p_type == elf::abi::PT_LOAD && phdr.p_flags & elf::abi::PF_X != 0 && phdr.p_filesz > 0 {
+                regions.push(CodeRegion {
+                    off: phdr.p_offset as usize,
+                    size: phdr.p_filesz as usize,
+                    addr: phdr.p_vaddr,
+                });
+            }
+        }
+    }
+    // Relocatable objects have no program headers, fall back on sections
+    if regions.is_empty() {
+        if let Some(shdrs) = elf.section_headers() {
+            for shdr in shdrs {
+                if shdr.sh_flags & elf::abi::SHF_EXECINSTR as u64 != 0 && shdr.sh_type != elf::abi::SHT_NOBITS && shdr.sh_size > 0 {
+                    regions.push(CodeRegion {
+                        off: shdr.sh_offset as usize,
+                        size: shdr.sh_size as usize,
+                        addr: shdr.sh_addr,
+                    });
+                }
             }
         }
     }
-    return Err(Box::new(RVError {msg: String::from("There is no .text section. The binary may be stripped")}));
+    regions.retain(|r| r.off.checked_add(r.size).is_some_and(|end| end <= len));
+    if regions.is_empty() {
+        return Err(Box::new(RVError {msg: String::from("There is no executable segment or section. The binary may be stripped")}));
+    }
+    return Ok(regions);
+}
+
+/// Lowest address of the loaded image, which is 0 for PIE binaries
+pub fn image_base(elf: &ElfBytes<endian::AnyEndian>) -> Option<u64> {
+    return elf.segments()?.iter()
+        .filter(|phdr| phdr.p_type == elf::abi::PT_LOAD)
+        .map(|phdr| phdr.p_vaddr)
+        .min();
+}
+
+/// Moves the regions of an image loaded at `from` so that it is loaded at `to`
+pub fn rebase(regions: &mut [CodeRegion], from: u64, to: u64) {
+    for region in regions {
+        region.addr = region.addr.wrapping_sub(from).wrapping_add(to);
+    }
+}
+
+/// Parses a Risc-V ELF file and returns its ISA, executable regions and image base
+pub fn load_elf(data: &[u8]) -> Result<(Arch, Vec<CodeRegion>, u64), RVError> {
+    let elf = match ElfBytes::<endian::AnyEndian>::minimal_parse(data) {
+        Ok(elf) => elf,
+        Err(_) => return Err(RVError {msg: String::from("Make sure to provide a valid ELF file")}),
+    };
+    if elf.ehdr.e_machine != elf::abi::EM_RISCV {
+        return Err(RVError {msg: String::from("racoonv only supports Risc-V binaries (ISA RV32GC/RV64GC)")});
+    }
+    match get_code_regions(&elf, data.len()) {
+        Ok(regions) => {
+            let base = image_base(&elf).unwrap_or_else(|| regions.iter().map(|r| r.addr).min().unwrap_or(0));
+            Ok((Arch::from_class(elf.ehdr.class), regions, base))
+        }
+        Err(e) => Err(RVError {msg: e.to_string()}),
+    }
 }
 
-pub fn find_gadget_roots<'a>(cs: &'a capstone::Capstone, code: &[u8], jr: Option<RegId>) -> Vec<GadgetRoot<'a>> {
+/// Collects the roots of the selected kinds, jumping to any of `jr` when not empty
+pub fn find_gadget_roots(cache: &InsnCache, jr: &[RegId], kinds: KindFilter) -> Vec<GadgetRoot> {
     let mut roots = Vec::new();
 
-    for off in (0..code.len()).step_by(ALIGNMENT) {
-        if let Ok(insns) = cs.disasm_count(&code[off..], off as u64, 1) {
-            if let Some(ins) = insns.first() {
-                if let Ok(ins) = GadgetInsn::create(cs, ins) {
-                    if is_branching(ins.id()) {
-                        for op in ins.operands() {
-                            if let RiscVOperand::Reg(reg) = op {
-                                if reg == &RegId(0) {
-                                    continue
-                                }
-                                if let Some(target) = jr {
-                                    if reg != &target {
-                                        break
-                                    }
-                                }
-                                roots.push(GadgetRoot::from(ins, off as u64));
-                                break;
-                            }
-                        }
-                    }
-                }
+    for (off, ins) in cache.iter() {
+        if let Some(kind) = RootKind::of(ins) {
+            if is_selected_root(ins, kind, jr, kinds) {
+                roots.push(GadgetRoot::from(ins.clone(), off, kind));
             }
         }
     }
     return roots;
 }
 
-pub fn find_gadgets_at_root<'a>(cs: &'a Capstone, root: GadgetRoot<'a>, addr: u64, code: &'a [u8], max: usize) -> Vec<Gadget<'a>> {
+/// Whether the root `ins` of the given kind is of the selected kinds, jumping
+/// to any of `jr` when not empty
+pub fn is_selected_root(ins: &GadgetInsn, kind: RootKind, jr: &[RegId], kinds: KindFilter) -> bool {
+    return kinds.accepts(kind) && (jr.is_empty() || ins.regs_read().last().is_some_and(|reg| jr.contains(reg)));
+}
+
+/// Decodes forward from `off` up to the first indirect branch, at most `max`
+/// instructions before it. An `ecall` at `off` is a gadget on its own when no
+/// indirect branch follows it within reach.
+pub fn gadget_at(cache: &InsnCache, off: u64, max: usize) -> Result<Gadget, RVError> {
+    let mut insns = Vec::new();
+    let mut cur = off;
+    let mut ecall = None;
+
+    while let Some(ins) = cache.at(cur) {
+        match RootKind::of(ins) {
+            // A step of the gadget, or an ecall site on its own when no jump follows it
+            Some(RootKind::Syscall) if insns.is_empty() => ecall = Some(ins.clone()),
+            Some(RootKind::Syscall) => {}
+            Some(_) if insns.is_empty() => break,
+            Some(kind) => return Gadget::create(GadgetRoot::from(ins.clone(), cur, kind), insns, cache.arch()),
+            None => {}
+        }
+        if is_branching(ins.id()) || insns.len() == max {
+            break;
+        }
+        insns.push(ins.clone());
+        cur += ins.bytes().len() as u64;
+    }
+    if let Some(ecall) = ecall {
+        return Gadget::create(GadgetRoot::from(ecall, off, RootKind::Syscall), Vec::new(), cache.arch());
+    }
+    return Err(RVError {msg: format!("there is no gadget of at most {} instructions at {:#x}", max, cache.addr() + off)});
+}
+
+pub fn find_gadgets_at_root(cache: &InsnCache, root: GadgetRoot, max: usize) -> Vec<Gadget> {
     let mut gadgets: Vec<Gadget> = Vec::new();
     let mut insns: Vec<GadgetInsn> = Vec::new();
 
-    disas_back_at(cs, &mut gadgets, root.clone(), &mut insns, addr, root.off, code, max);
+    disas_back_at(cache, &mut gadgets, root.clone(), &mut insns, root.off, max);
+    // Every ecall site is reported, even without instructions before it
+    if root.kind == RootKind::Syscall {
+        if let Ok(g) = Gadget::create(root, Vec::new(), cache.arch()) {
+            gadgets.push(g);
+        }
+    }
     return gadgets;
 }
 
-fn disas_back_at<'a>(cs: &'a Capstone, gadgets: &mut Vec<Gadget<'a>>, root: GadgetRoot<'a>, insns: &mut Vec<GadgetInsn<'a>>, addr: u64, off: u64, code: &'a [u8], max: usize) -> bool {
+fn disas_back_at(cache: &InsnCache, gadgets: &mut Vec<Gadget>, root: GadgetRoot, insns: &mut Vec<GadgetInsn>, off: u64, max: usize) -> bool {
     let mut found = false;
 
-    if max == 0 || off <= 0 {
+    if max == 0 || off == 0 {
         return false;
     }
     
     for i in (MIN_INSSZ as u64 ..= MAX_INSSZ as u64).step_by(ALIGNMENT) {
 
-        if i as u64 > off {
+        if i > off {
             break;
         }
-        if let Ok(ins) = cs.disasm_count(&code[(off - i) as usize..], addr + (off - i), 1) {
-            if let Some(ins) = ins.first() {
-                if ins.len() != i as usize {
-                    continue;
-                }
-                if is_branching(ins.id()) {
-                    break;
-                }
-                if let Ok(ins) = GadgetInsn::create(cs, ins) {
-                    insns.push(ins);
-                    if disas_back_at(cs, gadgets, root.clone(), insns, addr, off - i, code, max - 1) == false {
-                        if let Ok(g) = Gadget::create(root.clone(), insns.iter().rev().cloned().collect()) {
-                            gadgets.push(g);
-                            found = true;
-                        }
-                    } 
-                    insns.pop();
-                }
+        if let Some(ins) = cache.at(off - i) {
+            if ins.bytes().len() != i as usize {
+                continue;
+            }
+            if is_branching(ins.id()) {
+                break;
             }
+            insns.push(ins.clone());
+            if !disas_back_at(cache, gadgets, root.clone(), insns, off - i, max - 1) {
+                if let Ok(g) = Gadget::create(root.clone(), insns.iter().rev().cloned().collect(), cache.arch()) {
+                    gadgets.push(g);
+                    found = true;
+                }
+            } 
+            insns.pop();
         }
     }
     return found;
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::{json, Value};
+
+use crate::core::Arch;
+use crate::err::RVError;
+use crate::gadget::Gadget;
+
+/// Output format of a gadget diff
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffFormat {
+    Text,
+    Json,
+}
+
+pub fn diff_format_from_str(format: &str) -> Result<DiffFormat, RVError> {
+    match format {
+        "text" => Ok(DiffFormat::Text),
+        "json" => Ok(DiffFormat::Json),
+        _ => Err(RVError {msg: String::from("not a diff format (text, json)")}),
+    }
+}
+
+/// Gadgets of two versions of a binary, sorted by address
+pub struct GadgetDiff {
+    /// Found at the same address in both versions
+    pub stable: Vec<Gadget>,
+    /// Found in both versions at different addresses, old then new
+    pub relocated: Vec<(Gadget, Gadget)>,
+    /// Only found in the old version
+    pub removed: Vec<Gadget>,
+    /// Only found in the new version
+    pub added: Vec<Gadget>,
+    arch: Arch,
+}
+
+/// Occurrences of each gadget content, by address
+fn by_content(gadgets: &[Gadget]) -> HashMap<&Gadget, Vec<&Gadget>> {
+    let mut groups: HashMap<&Gadget, Vec<&Gadget>> = HashMap::new();
+    for gadget in gadgets {
+        groups.entry(gadget).or_default().push(gadget);
+    }
+    for group in groups.values_mut() {
+        group.sort_by_key(|g| g.address());
+    }
+    return groups;
+}
+
+/// Address shift found between the most occurrences of a content missing from
+/// the other version, the smallest one on ties
+fn dominant_delta(moved: &[(Vec<&Gadget>, Vec<&Gadget>)]) -> Option<i64> {
+    let mut counts: HashMap<i64, usize> = HashMap::new();
+    for (gone, came) in moved {
+        for o in gone {
+            for n in came {
+                *counts.entry(n.address().wrapping_sub(o.address()) as i64).or_default() += 1;
+            }
+        }
+    }
+    return counts.into_iter().max_by_key(|(delta, count)| (*count, std::cmp::Reverse(delta.unsigned_abs()))).map(|(delta, _)| delta);
+}
+
+/// Matches the gadgets of both versions by content, then by address. The
+/// occurrences of a content missing from the other version at the same address
+/// are paired as relocated when they moved by the shift most gadgets moved by,
+/// then in address order, the rest being removed or added.
+pub fn diff(old: &[Gadget], new: &[Gadget], arch: Arch) -> GadgetDiff {
+    let (old, new) = (by_content(old), by_content(new));
+    let mut diff = GadgetDiff {stable: Vec::new(), relocated: Vec::new(), removed: Vec::new(), added: Vec::new(), arch};
+
+    let mut moved = Vec::new();
+    for (content, olds) in &old {
+        let news = new.get(content).cloned().unwrap_or_default();
+        let gone: Vec<&Gadget> = olds.iter().copied().filter(|o| !news.iter().any(|n| n.address() == o.address())).collect();
+        let came: Vec<&Gadget> = news.iter().copied().filter(|n| !olds.iter().any(|o| o.address() == n.address())).collect();
+
+        diff.stable.extend(news.iter().filter(|n| olds.iter().any(|o| o.address() == n.address())).map(|&n| n.clone()));
+        moved.push((gone, came));
+    }
+    let delta = dominant_delta(&moved);
+    for (mut gone, mut came) in moved {
+        if let Some(delta) = delta {
+            gone.retain(|o| {
+                let Some(at) = came.iter().position(|n| n.address() == o.address().wrapping_add(delta as u64)) else {
+                    return true;
+                };
+                diff.relocated.push(((*o).clone(), came.remove(at).clone()));
+                false
+            });
+        }
+        diff.relocated.extend(gone.iter().zip(came.iter()).map(|(&o, &n)| (o.clone(), n.clone())));
+        diff.removed.extend(gone.iter().skip(came.len()).map(|&o| o.clone()));
+        diff.added.extend(came.iter().skip(gone.len()).map(|&n| n.clone()));
+    }
+    for (content, news) in &new {
+        if !old.contains_key(content) {
+            diff.added.extend(news.iter().map(|&n| n.clone()));
+        }
+    }
+    diff.stable.sort_by_key(|g| g.address());
+    diff.relocated.sort_by_key(|(o, _)| o.address());
+    diff.removed.sort_by_key(|g| g.address());
+    diff.added.sort_by_key(|g| g.address());
+    return diff;
+}
+
+fn record(gadget: &Gadget) -> Value {
+    return json!({"address": gadget.address(), "symbol": gadget.symbol(), "gadget": gadget.text()});
+}
+
+impl GadgetDiff {
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "stable": self.stable.iter().map(record).collect::<Vec<Value>>(),
+            "relocated": self.relocated.iter().map(|(o, n)| json!({
+                "old": o.address(),
+                "new": n.address(),
+                "old_symbol": o.symbol(),
+                "new_symbol": n.symbol(),
+                "gadget": n.text(),
+            })).collect::<Vec<Value>>(),
+            "removed": self.removed.iter().map(record).collect::<Vec<Value>>(),
+            "added": self.added.iter().map(record).collect::<Vec<Value>>(),
+            "summary": {
+                "stable": self.stable.len(),
+                "relocated": self.relocated.len(),
+                "removed": self.removed.len(),
+                "added": self.added.len(),
+            },
+        })
+    }
+
+}
+
+/// Lists the removed, added and relocated gadgets, the stable ones too with `{:#}`
+impl fmt::Display for GadgetDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = self.arch.addr_width() + 2;
+        let at = |g: &Gadget| format!("{:#0w$x}{}", g.address(), g.symbol().map(|s| format!(" <{}>", s)).unwrap_or_default(), w = w);
+
+        let mut sections = vec![("Removed", &self.removed), ("Added", &self.added)];
+        if f.alternate() {
+            sections.push(("Stable", &self.stable));
+        }
+        for (title, gadgets) in sections {
+            if !gadgets.is_empty() {
+                writeln!(f, "{} gadgets:", title)?;
+                for gadget in gadgets {
+                    writeln!(f, "  {}   {}", at(gadget), gadget.text())?;
+                }
+            }
+        }
+        if !self.relocated.is_empty() {
+            writeln!(f, "Relocated gadgets:")?;
+            for (o, n) in &self.relocated {
+                writeln!(f, "  {} -> {}   {}", at(o), at(n), n.text())?;
+            }
+        }
+        writeln!(f, "{} stable, {} relocated, {} removed and {} added gadgets.", self.stable.len(), self.relocated.len(), self.removed.len(), self.added.len())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Scanner;
+
+    /// Two `ret` epilogues ending with `addi16sp sp, 0x20; jr ra`, `ecall; ret`
+    /// and a gadget jumping through `s3`
+    const CODE: [u8; 30] = [
+        0x02, 0x65, 0xa2, 0x65, 0xe2, 0x60, 0x05, 0x61, 0x82, 0x80, 0xa2, 0x68,
+        0xc2, 0x60, 0x05, 0x61, 0x82, 0x80, 0x73, 0x00, 0x00, 0x00, 0x82, 0x80,
+        0x52, 0x85, 0x82, 0x89, 0x82, 0x89,
+    ];
+
+    fn gadgets(code: &[u8]) -> Vec<Gadget> {
+        return Scanner::new(code).arch(Arch::RV64).all_gadgets();
+    }
+
+    #[test]
+    fn relocations_follow_the_shift() {
+        // Two c.nop inserted at the start
+        let shifted: Vec<u8> = [0x01, 0x00, 0x01, 0x00].iter().chain(&CODE).copied().collect();
+        let diff = diff(&gadgets(&CODE), &gadgets(&shifted), Arch::RV64);
+
+        assert!(diff.stable.is_empty());
+        assert!(diff.relocated.iter().all(|(o, n)| n.address() == o.address() + 4));
+        let epilogue = |g: &Gadget| g.text() == "addi16sp sp, 0x20; jr ra";
+        let moved: Vec<(u64, u64)> = diff.relocated.iter().filter(|(o, _)| epilogue(o)).map(|(o, n)| (o.address(), n.address())).collect();
+        assert_eq!(moved, [(0x0e, 0x12)]);
+        let added: Vec<u64> = diff.added.iter().filter(|g| epilogue(g)).map(|g| g.address()).collect();
+        assert_eq!(added, [0x0a]);
+    }
+
+}
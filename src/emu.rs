@@ -0,0 +1,894 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use capstone::RegId;
+use capstone::arch::riscv::RiscVReg::*;
+
+use crate::core::{Arch, reg_name};
+use crate::err::RVError;
+use crate::gadget::{Gadget, GadgetInsn};
+
+/// Binary operations kept in symbolic expressions
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Sll,
+    Slt,
+    Sltu,
+    Xor,
+    Srl,
+    Sra,
+    Or,
+    And,
+    Mul,
+    Mulh,
+    Mulhsu,
+    Mulhu,
+    Div,
+    Divu,
+    Rem,
+    Remu,
+}
+
+impl BinOp {
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Sll => "<<",
+            BinOp::Slt => "<s",
+            BinOp::Sltu => "<u",
+            BinOp::Xor => "^",
+            BinOp::Srl => ">>u",
+            BinOp::Sra => ">>s",
+            BinOp::Or => "|",
+            BinOp::And => "&",
+            BinOp::Mul => "*",
+            BinOp::Mulh => "*h",
+            BinOp::Mulhsu => "*hsu",
+            BinOp::Mulhu => "*hu",
+            BinOp::Div => "/s",
+            BinOp::Divu => "/u",
+            BinOp::Rem => "%s",
+            BinOp::Remu => "%u",
+        }
+    }
+
+}
+
+/// Value of a register or memory location, relative to the initial state
+#[derive (Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Const(u64),
+    /// Initial value of a register
+    Reg(RegId),
+    /// Initial memory content at an address: size in bytes, sign extension
+    Load(Box<Expr>, u8, bool),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+    /// Sign extension of the low 32 bits (RV64 `*w` instructions)
+    Sext32(Box<Expr>),
+    /// Value produced outside of the emulated code (syscall result, CSR), or
+    /// memory mixing several stores
+    Opaque(String),
+}
+
+impl Expr {
+
+    pub fn as_const(&self) -> Option<u64> {
+        match self {
+            Expr::Const(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    /// Value before the sign extension of a RV64 `*w` instruction
+    pub fn strip_sext(&self) -> &Expr {
+        match self {
+            Expr::Sext32(inner) => inner,
+            _ => self,
+        }
+    }
+
+    /// Splits `expr + const` in its two parts
+    pub fn split_offset(&self) -> (&Expr, u64) {
+        match self {
+            Expr::Bin(BinOp::Add, lhs, rhs) => match rhs.as_ref() {
+                Expr::Const(c) => (lhs, *c),
+                _ => (self, 0),
+            },
+            _ => (self, 0),
+        }
+    }
+
+    /// Register whose initial value, plus a constant, gives the expression
+    pub fn offset_reg(&self) -> Option<RegId> {
+        match self.strip_sext().split_offset().0 {
+            Expr::Reg(reg) => Some(*reg),
+            _ => None,
+        }
+    }
+
+}
+
+impl fmt::Display for Expr {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Const(val) => write!(f, "{:#x}", val),
+            Expr::Reg(reg) => write!(f, "{}", reg_name(*reg)),
+            Expr::Load(addr, size, signed) => write!(f, "{}{}[{}]", if *signed { "i" } else { "u" }, size * 8, addr),
+            Expr::Bin(BinOp::Add, lhs, rhs) => match rhs.as_ref() {
+                Expr::Const(val) if (*val as i64) < 0 => write!(f, "{}-{:#x}", lhs, (*val as i64).unsigned_abs()),
+                _ => write!(f, "{}+{}", lhs, rhs),
+            },
+            Expr::Bin(op, lhs, rhs) => write!(f, "({} {} {})", lhs, op.symbol(), rhs),
+            Expr::Sext32(val) => write!(f, "sext32({})", val),
+            Expr::Opaque(name) => write!(f, "<{}>", name),
+        }
+    }
+
+}
+
+/// Store performed by the emulated code
+#[derive (Clone, Debug, PartialEq, Eq)]
+pub struct MemWrite {
+    pub addr: Expr,
+    pub size: u8,
+    pub value: Expr,
+}
+
+/// Observable effects of an emulated gadget
+#[derive (Clone, Debug, Default)]
+pub struct Effects {
+    /// Registers whose final value differs from their initial one
+    pub regs: Vec<(RegId, Expr)>,
+    pub writes: Vec<MemWrite>,
+    /// Address the gadget jumps to, `None` if it falls through
+    pub target: Option<Expr>,
+    /// Assumptions made during the emulation (untaken branches, syscalls)
+    pub notes: Vec<String>,
+}
+
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Lui,
+    Auipc,
+    Jal,
+    Jalr,
+    Branch(Cond),
+    Load(u8, bool),
+    Store(u8),
+    AluImm(BinOp, bool),
+    Alu(BinOp, bool),
+    Ecall,
+    Ebreak,
+    Csr,
+    Fence,
+}
+
+/// Integer instruction decoded from its encoding. Capstone aliases (`mv`,
+/// `neg`, `seqz`, `ret`...) drop operands, so fields are extracted from the
+/// instruction bytes instead.
+#[derive (Clone, Copy, Debug)]
+struct Decoded {
+    op: Op,
+    rd: usize,
+    rs1: usize,
+    rs2: usize,
+    imm: i64,
+}
+
+fn bits(ins: u32, hi: u32, lo: u32) -> u32 {
+    return (ins >> lo) & ((1 << (hi - lo + 1)) - 1);
+}
+
+fn sext(val: u64, width: u32) -> i64 {
+    let shift = 64 - width;
+    return ((val << shift) as i64) >> shift;
+}
+
+/// Symbolic base and constant offset of an address, `None` for absolute ones
+fn location(addr: &Expr) -> (Option<&Expr>, u64) {
+    match addr.split_offset() {
+        (Expr::Const(c), off) => (None, c.wrapping_add(off)),
+        (base, off) => (Some(base), off),
+    }
+}
+
+fn unsupported(ins: &GadgetInsn) -> RVError {
+    RVError {msg: format!("unsupported instruction '{}' at {:#x}", ins, ins.address())}
+}
+
+fn decode(ins: &GadgetInsn, arch: Arch) -> Result<Decoded, RVError> {
+    let bytes = ins.bytes();
+    match bytes.len() {
+        4 => decode32(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]), arch),
+        2 => decode16(u16::from_le_bytes([bytes[0], bytes[1]]) as u32, arch),
+        _ => None,
+    }.ok_or_else(|| unsupported(ins))
+}
+
+fn decode32(ins: u32, arch: Arch) -> Option<Decoded> {
+    let rd = bits(ins, 11, 7) as usize;
+    let rs1 = bits(ins, 19, 15) as usize;
+    let rs2 = bits(ins, 24, 20) as usize;
+    let funct3 = bits(ins, 14, 12);
+    let funct7 = bits(ins, 31, 25);
+    let imm_i = sext(bits(ins, 31, 20) as u64, 12);
+    let imm_s = sext(((bits(ins, 31, 25) << 5) | bits(ins, 11, 7)) as u64, 12);
+    let imm_b = sext(((bits(ins, 31, 31) << 12) | (bits(ins, 7, 7) << 11) | (bits(ins, 30, 25) << 5) | (bits(ins, 11, 8) << 1)) as u64, 13);
+    let imm_u = sext((ins & 0xfffff000) as u64, 32);
+    let imm_j = sext(((bits(ins, 31, 31) << 20) | (bits(ins, 19, 12) << 12) | (bits(ins, 20, 20) << 11) | (bits(ins, 30, 21) << 1)) as u64, 21);
+    let rv64 = arch == Arch::RV64;
+    let dec = |op, imm| Some(Decoded { op, rd, rs1, rs2, imm });
+
+    match bits(ins, 6, 0) {
+        0x37 => dec(Op::Lui, imm_u),
+        0x17 => dec(Op::Auipc, imm_u),
+        0x6f => dec(Op::Jal, imm_j),
+        0x67 if funct3 == 0 => dec(Op::Jalr, imm_i),
+        0x63 => {
+            let cond = match funct3 {
+                0 => Cond::Eq,
+                1 => Cond::Ne,
+                4 => Cond::Lt,
+                5 => Cond::Ge,
+                6 => Cond::Ltu,
+                7 => Cond::Geu,
+                _ => return None,
+            };
+            dec(Op::Branch(cond), imm_b)
+        }
+        0x03 => {
+            let (size, signed) = match funct3 {
+                0 => (1, true),
+                1 => (2, true),
+                2 => (4, true),
+                3 if rv64 => (8, true),
+                4 => (1, false),
+                5 => (2, false),
+                6 if rv64 => (4, false),
+                _ => return None,
+            };
+            dec(Op::Load(size, signed), imm_i)
+        }
+        0x23 => match funct3 {
+            0..=2 => dec(Op::Store(1 << funct3), imm_s),
+            3 if rv64 => dec(Op::Store(8), imm_s),
+            _ => None,
+        },
+        0x13 => {
+            let shamt = if rv64 { bits(ins, 25, 20) } else { bits(ins, 24, 20) } as i64;
+            match funct3 {
+                0 => dec(Op::AluImm(BinOp::Add, false), imm_i),
+                1 => dec(Op::AluImm(BinOp::Sll, false), shamt),
+                2 => dec(Op::AluImm(BinOp::Slt, false), imm_i),
+                3 => dec(Op::AluImm(BinOp::Sltu, false), imm_i),
+                4 => dec(Op::AluImm(BinOp::Xor, false), imm_i),
+                5 if bits(ins, 30, 30) == 1 => dec(Op::AluImm(BinOp::Sra, false), shamt),
+                5 => dec(Op::AluImm(BinOp::Srl, false), shamt),
+                6 => dec(Op::AluImm(BinOp::Or, false), imm_i),
+                _ => dec(Op::AluImm(BinOp::And, false), imm_i),
+            }
+        }
+        0x1b if rv64 => {
+            let shamt = bits(ins, 24, 20) as i64;
+            match funct3 {
+                0 => dec(Op::AluImm(BinOp::Add, true), imm_i),
+                1 => dec(Op::AluImm(BinOp::Sll, true), shamt),
+                5 if bits(ins, 30, 30) == 1 => dec(Op::AluImm(BinOp::Sra, true), shamt),
+                5 => dec(Op::AluImm(BinOp::Srl, true), shamt),
+                _ => None,
+            }
+        }
+        opcode @ (0x33 | 0x3b) => {
+            let word = opcode == 0x3b;
+            if word && !rv64 {
+                return None;
+            }
+            let op = match (funct7, funct3) {
+                (0x00, 0) => BinOp::Add,
+                (0x20, 0) => BinOp::Sub,
+                (0x00, 1) => BinOp::Sll,
+                (0x00, 2) if !word => BinOp::Slt,
+                (0x00, 3) if !word => BinOp::Sltu,
+                (0x00, 4) if !word => BinOp::Xor,
+                (0x00, 5) => BinOp::Srl,
+                (0x20, 5) => BinOp::Sra,
+                (0x00, 6) if !word => BinOp::Or,
+                (0x00, 7) if !word => BinOp::And,
+                (0x01, 0) => BinOp::Mul,
+                (0x01, 1) if !word => BinOp::Mulh,
+                (0x01, 2) if !word => BinOp::Mulhsu,
+                (0x01, 3) if !word => BinOp::Mulhu,
+                (0x01, 4) => BinOp::Div,
+                (0x01, 5) => BinOp::Divu,
+                (0x01, 6) => BinOp::Rem,
+                (0x01, 7) => BinOp::Remu,
+                _ => return None,
+            };
+            dec(Op::Alu(op, word), 0)
+        }
+        0x73 => match (funct3, ins >> 7) {
+            (0, 0) => dec(Op::Ecall, 0),
+            (0, 0x2000) => dec(Op::Ebreak, 0),
+            (0, _) => None,
+            (4, _) => None,
+            _ => dec(Op::Csr, bits(ins, 31, 20) as i64),
+        },
+        0x0f => dec(Op::Fence, 0),
+        _ => None,
+    }
+}
+
+fn decode16(ins: u32, arch: Arch) -> Option<Decoded> {
+    let rv64 = arch == Arch::RV64;
+    let funct3 = bits(ins, 15, 13);
+    let rd = bits(ins, 11, 7) as usize;
+    let rs2 = bits(ins, 6, 2) as usize;
+    // Registers x8-x15 of the 3 bits fields
+    let rdp = bits(ins, 4, 2) as usize + 8;
+    let rs1p = bits(ins, 9, 7) as usize + 8;
+    let imm6 = sext(((bits(ins, 12, 12) << 5) | bits(ins, 6, 2)) as u64, 6);
+    let uimm_w = ((bits(ins, 12, 10) << 3) | (bits(ins, 6, 6) << 2) | (bits(ins, 5, 5) << 6)) as i64;
+    let uimm_d = ((bits(ins, 12, 10) << 3) | (bits(ins, 6, 5) << 6)) as i64;
+    let dec = |op, rd, rs1, rs2, imm| Some(Decoded { op, rd, rs1, rs2, imm });
+
+    match (bits(ins, 1, 0), funct3) {
+        (0, 0) => {
+            let imm = ((bits(ins, 12, 11) << 4) | (bits(ins, 10, 7) << 6) | (bits(ins, 6, 6) << 2) | (bits(ins, 5, 5) << 3)) as i64;
+            if imm == 0 {
+                return None;
+            }
+            dec(Op::AluImm(BinOp::Add, false), rdp, 2, 0, imm)
+        }
+        (0, 2) => dec(Op::Load(4, true), rdp, rs1p, 0, uimm_w),
+        (0, 3) if rv64 => dec(Op::Load(8, true), rdp, rs1p, 0, uimm_d),
+        (0, 6) => dec(Op::Store(4), 0, rs1p, rdp, uimm_w),
+        (0, 7) if rv64 => dec(Op::Store(8), 0, rs1p, rdp, uimm_d),
+        (1, 0) => dec(Op::AluImm(BinOp::Add, false), rd, rd, 0, imm6),
+        (1, 1) if rv64 => dec(Op::AluImm(BinOp::Add, true), rd, rd, 0, imm6),
+        (1, 1) | (1, 5) => {
+            let imm = sext(((bits(ins, 12, 12) << 11) | (bits(ins, 11, 11) << 4) | (bits(ins, 10, 9) << 8)
+                | (bits(ins, 8, 8) << 10) | (bits(ins, 7, 7) << 6) | (bits(ins, 6, 6) << 7)
+                | (bits(ins, 5, 3) << 1) | (bits(ins, 2, 2) << 5)) as u64, 12);
+            dec(Op::Jal, if funct3 == 1 { 1 } else { 0 }, 0, 0, imm)
+        }
+        (1, 2) => dec(Op::AluImm(BinOp::Add, false), rd, 0, 0, imm6),
+        (1, 3) if rd == 2 => {
+            let imm = sext(((bits(ins, 12, 12) << 9) | (bits(ins, 6, 6) << 4) | (bits(ins, 5, 5) << 6)
+                | (bits(ins, 4, 3) << 7) | (bits(ins, 2, 2) << 5)) as u64, 10);
+            dec(Op::AluImm(BinOp::Add, false), 2, 2, 0, imm)
+        }
+        (1, 3) => dec(Op::Lui, rd, 0, 0, imm6 << 12),
+        (1, 4) => {
+            let shamt = ((bits(ins, 12, 12) << 5) | bits(ins, 6, 2)) as i64;
+            match (bits(ins, 11, 10), bits(ins, 12, 12), bits(ins, 6, 5)) {
+                (0, _, _) => dec(Op::AluImm(BinOp::Srl, false), rs1p, rs1p, 0, shamt),
+                (1, _, _) => dec(Op::AluImm(BinOp::Sra, false), rs1p, rs1p, 0, shamt),
+                (2, _, _) => dec(Op::AluImm(BinOp::And, false), rs1p, rs1p, 0, imm6),
+                (_, 0, 0) => dec(Op::Alu(BinOp::Sub, false), rs1p, rs1p, rdp, 0),
+                (_, 0, 1) => dec(Op::Alu(BinOp::Xor, false), rs1p, rs1p, rdp, 0),
+                (_, 0, 2) => dec(Op::Alu(BinOp::Or, false), rs1p, rs1p, rdp, 0),
+                (_, 0, _) => dec(Op::Alu(BinOp::And, false), rs1p, rs1p, rdp, 0),
+                (_, 1, 0) if rv64 => dec(Op::Alu(BinOp::Sub, true), rs1p, rs1p, rdp, 0),
+                (_, 1, 1) if rv64 => dec(Op::Alu(BinOp::Add, true), rs1p, rs1p, rdp, 0),
+                _ => None,
+            }
+        }
+        (1, 6) | (1, 7) => {
+            let imm = sext(((bits(ins, 12, 12) << 8) | (bits(ins, 11, 10) << 3) | (bits(ins, 6, 5) << 6)
+                | (bits(ins, 4, 3) << 1) | (bits(ins, 2, 2) << 5)) as u64, 9);
+            let cond = if funct3 == 6 { Cond::Eq } else { Cond::Ne };
+            dec(Op::Branch(cond), 0, rs1p, 0, imm)
+        }
+        (2, 0) => dec(Op::AluImm(BinOp::Sll, false), rd, rd, 0, ((bits(ins, 12, 12) << 5) | bits(ins, 6, 2)) as i64),
+        (2, 2) => {
+            let imm = ((bits(ins, 12, 12) << 5) | (bits(ins, 6, 4) << 2) | (bits(ins, 3, 2) << 6)) as i64;
+            dec(Op::Load(4, true), rd, 2, 0, imm)
+        }
+        (2, 3) if rv64 => {
+            let imm = ((bits(ins, 12, 12) << 5) | (bits(ins, 6, 5) << 3) | (bits(ins, 4, 2) << 6)) as i64;
+            dec(Op::Load(8, true), rd, 2, 0, imm)
+        }
+        (2, 4) => match (bits(ins, 12, 12), rd, rs2) {
+            (0, 0, _) => None,
+            (0, _, 0) => dec(Op::Jalr, 0, rd, 0, 0),
+            (0, _, _) => dec(Op::Alu(BinOp::Add, false), rd, 0, rs2, 0),
+            (_, 0, 0) => dec(Op::Ebreak, 0, 0, 0, 0),
+            (_, _, 0) => dec(Op::Jalr, 1, rd, 0, 0),
+            _ => dec(Op::Alu(BinOp::Add, false), rd, rd, rs2, 0),
+        },
+        (2, 6) => {
+            let imm = ((bits(ins, 12, 9) << 2) | (bits(ins, 8, 7) << 6)) as i64;
+            dec(Op::Store(4), 0, 2, rs2, imm)
+        }
+        (2, 7) if rv64 => {
+            let imm = ((bits(ins, 12, 10) << 3) | (bits(ins, 9, 7) << 6)) as i64;
+            dec(Op::Store(8), 0, 2, rs2, imm)
+        }
+        _ => None,
+    }
+}
+
+/// Interpreter for the RV32/RV64 integer instructions (I, M and C extensions),
+/// with symbolic registers and a sparse memory
+pub struct Emulator {
+    arch: Arch,
+    regs: Vec<Expr>,
+    /// Bytes known at constant addresses
+    bytes: BTreeMap<u64, u8>,
+    writes: Vec<MemWrite>,
+    notes: Vec<String>,
+}
+
+impl Emulator {
+
+    /// Creates an emulator where every register holds its symbolic initial value
+    pub fn new(arch: Arch) -> Self {
+        let mut regs: Vec<Expr> = (RISCV_REG_X0..=RISCV_REG_X31).map(|r| Expr::Reg(RegId(r as u16))).collect();
+        regs[0] = Expr::Const(0);
+        return Emulator {
+            arch,
+            regs,
+            bytes: BTreeMap::new(),
+            writes: Vec::new(),
+            notes: Vec::new(),
+        };
+    }
+
+    fn index(reg: RegId) -> Result<usize, RVError> {
+        let id = reg.0 as u32;
+        if !(RISCV_REG_X0..=RISCV_REG_X31).contains(&id) {
+            return Err(RVError {msg: format!("'{}' is not an integer register", reg_name(reg))});
+        }
+        return Ok((id - RISCV_REG_X0) as usize);
+    }
+
+    /// Gives a concrete initial value to a register
+    pub fn set_reg(&mut self, reg: RegId, val: u64) -> Result<(), RVError> {
+        let idx = Emulator::index(reg)?;
+        if idx != 0 {
+            self.regs[idx] = Expr::Const(self.trunc(val));
+        }
+        return Ok(());
+    }
+
+    /// Gives a concrete initial content to memory
+    pub fn set_mem(&mut self, addr: u64, data: &[u8]) {
+        for (i, b) in data.iter().enumerate() {
+            self.bytes.insert(addr.wrapping_add(i as u64), *b);
+        }
+    }
+
+    pub fn reg(&self, reg: RegId) -> Result<&Expr, RVError> {
+        return Ok(&self.regs[Emulator::index(reg)?]);
+    }
+
+    fn xlen(&self) -> u32 {
+        match self.arch {
+            Arch::RV32 => 32,
+            Arch::RV64 => 64,
+        }
+    }
+
+    fn trunc(&self, val: u64) -> u64 {
+        return self.arch.trunc(val);
+    }
+
+    fn signed(&self, val: u64) -> i64 {
+        return sext(val, self.xlen());
+    }
+
+    fn write_reg(&mut self, rd: usize, val: Expr) {
+        if rd != 0 {
+            self.regs[rd] = val;
+        }
+    }
+
+    fn add(&self, lhs: Expr, imm: i64) -> Expr {
+        return self.bin(BinOp::Add, lhs, Expr::Const(self.trunc(imm as u64)));
+    }
+
+    /// Applies `op`, folding constants and keeping `expr + const` canonical
+    fn bin(&self, op: BinOp, lhs: Expr, rhs: Expr) -> Expr {
+        if let (Some(a), Some(b)) = (lhs.as_const(), rhs.as_const()) {
+            if let Some(val) = self.eval(op, a, b) {
+                return Expr::Const(val);
+            }
+        }
+        match (op, lhs, rhs) {
+            (BinOp::Add, Expr::Const(0), other) | (BinOp::Add, other, Expr::Const(0)) => other,
+            (BinOp::Sub, other, Expr::Const(0)) => other,
+            (BinOp::Sub, lhs, Expr::Const(c)) => self.bin(BinOp::Add, lhs, Expr::Const(self.trunc(c.wrapping_neg()))),
+            (BinOp::Add, Expr::Const(c), other) => self.bin(BinOp::Add, other, Expr::Const(c)),
+            (BinOp::Add, Expr::Bin(BinOp::Add, inner, c1), Expr::Const(c2)) if c1.as_const().is_some() => {
+                let c = self.trunc(c1.as_const().unwrap_or(0).wrapping_add(c2));
+                self.bin(BinOp::Add, *inner, Expr::Const(c))
+            }
+            (BinOp::Or | BinOp::Xor | BinOp::Sll | BinOp::Srl | BinOp::Sra, other, Expr::Const(0)) => other,
+            (BinOp::Or | BinOp::Xor, Expr::Const(0), other) => other,
+            (BinOp::And | BinOp::Mul, Expr::Const(0), _) | (BinOp::And | BinOp::Mul, _, Expr::Const(0)) => Expr::Const(0),
+            (BinOp::Mul, other, Expr::Const(1)) | (BinOp::Mul, Expr::Const(1), other) => other,
+            (op, lhs, rhs) => Expr::Bin(op, Box::new(lhs), Box::new(rhs)),
+        }
+    }
+
+    fn eval(&self, op: BinOp, a: u64, b: u64) -> Option<u64> {
+        let xlen = self.xlen();
+        let shamt = (b & (xlen as u64 - 1)) as u32;
+        let (sa, sb) = (self.signed(a), self.signed(b));
+        let high = |prod: i128| (prod >> xlen) as u64;
+        let val = match op {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::Sll => a << shamt,
+            BinOp::Slt => (sa < sb) as u64,
+            BinOp::Sltu => (a < b) as u64,
+            BinOp::Xor => a ^ b,
+            BinOp::Srl => a >> shamt,
+            BinOp::Sra => (sa >> shamt) as u64,
+            BinOp::Or => a | b,
+            BinOp::And => a & b,
+            BinOp::Mul => a.wrapping_mul(b),
+            BinOp::Mulh => high(sa as i128 * sb as i128),
+            BinOp::Mulhsu => high(sa as i128 * b as i128),
+            BinOp::Mulhu => ((a as u128 * b as u128) >> xlen) as u64,
+            BinOp::Div if b == 0 => u64::MAX,
+            BinOp::Div => sa.wrapping_div(sb) as u64,
+            BinOp::Divu if b == 0 => u64::MAX,
+            BinOp::Divu => a / b,
+            BinOp::Rem if b == 0 => a,
+            BinOp::Rem => sa.wrapping_rem(sb) as u64,
+            BinOp::Remu if b == 0 => a,
+            BinOp::Remu => a % b,
+        };
+        return Some(self.trunc(val));
+    }
+
+    /// Result of a RV64 `*w` instruction: operation on the low 32 bits, sign extended
+    fn word(&self, op: BinOp, lhs: Expr, rhs: Expr) -> Expr {
+        if let (Some(a), Some(b)) = (lhs.as_const(), rhs.as_const()) {
+            let (a32, b32) = (a as u32, b as u32);
+            let val = match op {
+                BinOp::Sll => Some(a32.wrapping_shl(b32 & 31)),
+                BinOp::Srl => Some(a32.wrapping_shr(b32 & 31)),
+                BinOp::Sra => Some(((a32 as i32) >> (b32 & 31)) as u32),
+                BinOp::Div if b32 != 0 => Some((a32 as i32).wrapping_div(b32 as i32) as u32),
+                BinOp::Rem if b32 != 0 => Some((a32 as i32).wrapping_rem(b32 as i32) as u32),
+                BinOp::Divu if b32 != 0 => Some(a32 / b32),
+                BinOp::Remu if b32 != 0 => Some(a32 % b32),
+                BinOp::Div | BinOp::Divu => Some(u32::MAX),
+                BinOp::Rem | BinOp::Remu => Some(a32),
+                _ => self.eval(op, a, b).map(|v| v as u32),
+            };
+            if let Some(val) = val {
+                return Expr::Const(val as i32 as i64 as u64);
+            }
+        }
+        return Expr::Sext32(Box::new(self.bin(op, lhs, rhs)));
+    }
+
+    fn load(&self, addr: Expr, size: u8, signed: bool) -> Expr {
+        let extend = |val: u64| {
+            let val = if signed && size < 8 { sext(val, size as u32 * 8) as u64 } else { val };
+            Expr::Const(self.trunc(val))
+        };
+        let known = addr.as_const().and_then(|base| {
+            let known: Option<Vec<u8>> = (0..size as u64).map(|i| self.bytes.get(&base.wrapping_add(i)).copied()).collect();
+            known.map(|known| known.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+        });
+        // The last store overlapping the loaded bytes gives their value
+        let (base, off) = location(&addr);
+        for write in self.writes.iter().rev() {
+            let (wbase, woff) = location(&write.addr);
+            let start = woff.wrapping_sub(off) as i64;
+            if wbase != base || start >= size as i64 || start + write.size as i64 <= 0 {
+                continue;
+            }
+            // Little endian: a load at the address of a wider store reads its low bytes
+            if start == 0 && write.size >= size {
+                return match write.value.as_const() {
+                    Some(val) if size < 8 => extend(val & ((1u64 << (size * 8)) - 1)),
+                    Some(val) => extend(val),
+                    None => self.narrow(write.value.clone(), size, signed),
+                };
+            }
+            return match known {
+                Some(val) => extend(val),
+                None => Expr::Opaque(format!("{}{}[{}] partially stored", if signed { "i" } else { "u" }, size * 8, addr)),
+            };
+        }
+        if let Some(val) = known {
+            return extend(val);
+        }
+        return Expr::Load(Box::new(addr), size, signed);
+    }
+
+    /// Value a load of `size` bytes reads back from the register `val` stored at
+    /// its address, sign or zero extended
+    fn narrow(&self, val: Expr, size: u8, signed: bool) -> Expr {
+        let bits = size as u32 * 8;
+        if bits >= self.xlen() {
+            return val;
+        }
+        if !signed {
+            return self.bin(BinOp::And, val, Expr::Const((1u64 << bits) - 1));
+        }
+        if bits == 32 {
+            return match val {
+                Expr::Sext32(_) => val,
+                val => Expr::Sext32(Box::new(val)),
+            };
+        }
+        let shift = Expr::Const((self.xlen() - bits) as u64);
+        return self.bin(BinOp::Sra, self.bin(BinOp::Sll, val, shift.clone()), shift);
+    }
+
+    fn store(&mut self, addr: Expr, size: u8, value: Expr) {
+        if let Some(base) = addr.as_const() {
+            for i in 0..size as u64 {
+                match value.as_const() {
+                    Some(val) => self.bytes.insert(base.wrapping_add(i), (val >> (8 * i)) as u8),
+                    None => self.bytes.remove(&base.wrapping_add(i)),
+                };
+            }
+        }
+        let value = match value.as_const() {
+            Some(val) if size < 8 => Expr::Const(val & ((1u64 << (size * 8)) - 1)),
+            _ => value,
+        };
+        self.writes.push(MemWrite { addr, size, value });
+    }
+
+    /// Executes one instruction. Returns the jump target when control leaves
+    /// the sequence, conditional branches are assumed not taken unless constant.
+    pub fn step(&mut self, ins: &GadgetInsn) -> Result<Option<Expr>, RVError> {
+        let d = decode(ins, self.arch)?;
+        let pc = ins.address();
+        let next = pc.wrapping_add(ins.bytes().len() as u64);
+        let rs1 = self.regs[d.rs1].clone();
+        let rs2 = self.regs[d.rs2].clone();
+
+        match d.op {
+            Op::Lui => self.write_reg(d.rd, Expr::Const(self.trunc(d.imm as u64))),
+            Op::Auipc => self.write_reg(d.rd, Expr::Const(self.trunc(pc.wrapping_add(d.imm as u64)))),
+            Op::Jal => {
+                self.write_reg(d.rd, Expr::Const(self.trunc(next)));
+                return Ok(Some(Expr::Const(self.trunc(pc.wrapping_add(d.imm as u64)))));
+            }
+            Op::Jalr => {
+                let target = self.bin(BinOp::And, self.add(rs1, d.imm), Expr::Const(self.trunc(!1)));
+                let target = match target {
+                    // Code addresses are 2-bytes aligned, drop the mask on symbolic targets
+                    Expr::Bin(BinOp::And, inner, _) => *inner,
+                    other => other,
+                };
+                self.write_reg(d.rd, Expr::Const(self.trunc(next)));
+                return Ok(Some(target));
+            }
+            Op::Branch(cond) => {
+                let taken = match (rs1.as_const(), rs2.as_const()) {
+                    (Some(a), Some(b)) => Some(match cond {
+                        Cond::Eq => a == b,
+                        Cond::Ne => a != b,
+                        Cond::Lt => self.signed(a) < self.signed(b),
+                        Cond::Ge => self.signed(a) >= self.signed(b),
+                        Cond::Ltu => a < b,
+                        Cond::Geu => a >= b,
+                    }),
+                    _ if rs1 == rs2 => Some(matches!(cond, Cond::Eq | Cond::Ge | Cond::Geu)),
+                    _ => None,
+                };
+                match taken {
+                    Some(true) => {
+                        self.notes.push(format!("branch at {:#x} is taken", pc));
+                        return Ok(Some(Expr::Const(self.trunc(pc.wrapping_add(d.imm as u64)))));
+                    }
+                    Some(false) => {}
+                    None => self.notes.push(format!("branch at {:#x} assumed not taken", pc)),
+                }
+            }
+            Op::Load(size, signed) => {
+                let val = self.load(self.add(rs1, d.imm), size, signed);
+                self.write_reg(d.rd, val);
+            }
+            Op::Store(size) => {
+                let addr = self.add(rs1, d.imm);
+                self.store(addr, size, rs2);
+            }
+            Op::AluImm(op, word) => {
+                let imm = Expr::Const(self.trunc(d.imm as u64));
+                let val = if word { self.word(op, rs1, imm) } else { self.bin(op, rs1, imm) };
+                self.write_reg(d.rd, val);
+            }
+            Op::Alu(op, word) => {
+                let val = if word { self.word(op, rs1, rs2) } else { self.bin(op, rs1, rs2) };
+                self.write_reg(d.rd, val);
+            }
+            Op::Ecall => {
+                let a7 = self.regs[(RISCV_REG_A7 - RISCV_REG_X0) as usize].clone();
+                self.notes.push(format!("ecall at {:#x} with a7 = {}", pc, a7));
+                self.write_reg((RISCV_REG_A0 - RISCV_REG_X0) as usize, Expr::Opaque(format!("ecall@{:#x}", pc)));
+            }
+            Op::Ebreak => self.notes.push(format!("ebreak at {:#x}", pc)),
+            Op::Csr => self.write_reg(d.rd, Expr::Opaque(format!("csr {:#x}", d.imm))),
+            Op::Fence => {}
+        }
+        return Ok(None);
+    }
+
+    /// Runs the gadget, its root included, and reports its effects
+    pub fn run(&mut self, gadget: &Gadget) -> Result<Effects, RVError> {
+        let initial = self.regs.clone();
+        let mut target = None;
+        for ins in gadget.insns().iter().chain(std::iter::once(gadget.root())) {
+            target = self.step(ins)?;
+            if target.is_some() {
+                break;
+            }
+        }
+        let regs = self.regs.iter().zip(initial.iter()).enumerate().skip(1)
+            .filter(|(_, (val, init))| val != init)
+            .map(|(i, (val, _))| (RegId((RISCV_REG_X0 as usize + i) as u16), val.clone()))
+            .collect();
+        return Ok(Effects {
+            regs,
+            writes: self.writes.clone(),
+            target,
+            notes: self.notes.clone(),
+        });
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use capstone::arch::riscv::RiscVReg;
+
+    use super::*;
+    use crate::cache::InsnCache;
+
+    const ADDR: u64 = 0x1000;
+
+    fn reg(reg: RiscVReg::Type) -> RegId {
+        return RegId(reg as u16);
+    }
+
+    fn plus(reg: RiscVReg::Type, off: i64) -> Expr {
+        return Expr::Bin(BinOp::Add, Box::new(Expr::Reg(RegId(reg as u16))), Box::new(Expr::Const(off as u64)));
+    }
+
+    /// Steps through `code` mapped at `ADDR`, returning the last jump target
+    fn run(emu: &mut Emulator, code: &[u8]) -> Option<Expr> {
+        let cache = InsnCache::build(emu.arch, code, ADDR);
+        let mut off = 0;
+        let mut target = None;
+        while let Some(ins) = cache.at(off) {
+            target = emu.step(ins).unwrap();
+            off += ins.bytes().len() as u64;
+        }
+        assert_eq!(off, code.len() as u64, "code does not fully decode");
+        return target;
+    }
+
+    #[test]
+    fn compressed_stack_immediates() {
+        let mut emu = Emulator::new(Arch::RV64);
+        // c.addi16sp sp, -64; c.addi4spn a0, sp, 16
+        run(&mut emu, &[0x39, 0x71, 0x08, 0x08]);
+        assert_eq!(emu.reg(reg(RISCV_REG_SP)).unwrap(), &plus(RISCV_REG_SP, -64));
+        assert_eq!(emu.reg(reg(RISCV_REG_A0)).unwrap(), &plus(RISCV_REG_SP, -48));
+    }
+
+    #[test]
+    fn compressed_stack_loads_and_stores() {
+        let mut emu = Emulator::new(Arch::RV64);
+        // c.ldsp a0, 8(sp); c.sdsp a1, 16(sp); c.lwsp a2, 12(sp); c.swsp a1, 4(sp)
+        run(&mut emu, &[0x22, 0x65, 0x2e, 0xe8, 0x32, 0x46, 0x2e, 0xc2]);
+        assert_eq!(emu.reg(reg(RISCV_REG_A0)).unwrap(), &Expr::Load(Box::new(plus(RISCV_REG_SP, 8)), 8, true));
+        assert_eq!(emu.reg(reg(RISCV_REG_A2)).unwrap(), &Expr::Load(Box::new(plus(RISCV_REG_SP, 12)), 4, true));
+        let writes: Vec<(Expr, u8)> = emu.writes.iter().map(|w| (w.addr.clone(), w.size)).collect();
+        assert_eq!(writes, vec![(plus(RISCV_REG_SP, 16), 8), (plus(RISCV_REG_SP, 4), 4)]);
+        assert!(emu.writes.iter().all(|w| w.value == Expr::Reg(reg(RISCV_REG_A1))));
+    }
+
+    #[test]
+    fn compressed_jump() {
+        // c.j -6
+        let mut emu = Emulator::new(Arch::RV32);
+        assert_eq!(run(&mut emu, &[0xed, 0xbf]), Some(Expr::Const(ADDR - 6)));
+    }
+
+    #[test]
+    fn compressed_branch() {
+        // c.beqz a0, 8
+        let mut emu = Emulator::new(Arch::RV64);
+        emu.set_reg(reg(RISCV_REG_A0), 0).unwrap();
+        assert_eq!(run(&mut emu, &[0x01, 0xc5]), Some(Expr::Const(ADDR + 8)));
+
+        let mut emu = Emulator::new(Arch::RV64);
+        emu.set_reg(reg(RISCV_REG_A0), 1).unwrap();
+        assert_eq!(run(&mut emu, &[0x01, 0xc5]), None);
+
+        let mut emu = Emulator::new(Arch::RV64);
+        assert_eq!(run(&mut emu, &[0x01, 0xc5]), None);
+        assert_eq!(emu.notes, vec![format!("branch at {:#x} assumed not taken", ADDR)]);
+    }
+
+    #[test]
+    fn jalr_masks_the_low_bit() {
+        // jr 5(t0)
+        let code = [0x67, 0x80, 0x52, 0x00];
+        let mut emu = Emulator::new(Arch::RV32);
+        emu.set_reg(reg(RISCV_REG_T0), 0x2000).unwrap();
+        assert_eq!(run(&mut emu, &code), Some(Expr::Const(0x2004)));
+
+        let mut emu = Emulator::new(Arch::RV64);
+        assert_eq!(run(&mut emu, &code), Some(plus(RISCV_REG_T0, 5)));
+    }
+
+    #[test]
+    fn store_to_load_forwarding() {
+        // sd a0, 0(sp); ld a2, 0(sp)
+        let mut emu = Emulator::new(Arch::RV64);
+        run(&mut emu, &[0x2a, 0xe0, 0x02, 0x66]);
+        assert_eq!(emu.reg(reg(RISCV_REG_A2)).unwrap(), &Expr::Reg(reg(RISCV_REG_A0)));
+    }
+
+    #[test]
+    fn forwarding_extends_narrow_loads() {
+        let a0 = || Box::new(Expr::Reg(reg(RISCV_REG_A0)));
+        let forwarded = |code: &[u8]| {
+            let mut emu = Emulator::new(Arch::RV64);
+            run(&mut emu, code);
+            emu.reg(reg(RISCV_REG_A2)).unwrap().clone()
+        };
+        // sw a0, 0(sp); lw a2, 0(sp)
+        assert_eq!(forwarded(&[0x2a, 0xc0, 0x02, 0x46]), Expr::Sext32(a0()));
+        // sd a0, 0(sp); lw a2, 0(sp)
+        assert_eq!(forwarded(&[0x2a, 0xe0, 0x02, 0x46]), Expr::Sext32(a0()));
+        // sw a0, 0(sp); lwu a2, 0(sp)
+        assert_eq!(forwarded(&[0x2a, 0xc0, 0x03, 0x66, 0x01, 0x00]), Expr::Bin(BinOp::And, a0(), Box::new(Expr::Const(0xffffffff))));
+        // sb a0, 0(sp); lb a2, 0(sp)
+        let shl = Expr::Bin(BinOp::Sll, a0(), Box::new(Expr::Const(56)));
+        assert_eq!(forwarded(&[0x23, 0x00, 0xa1, 0x00, 0x03, 0x06, 0x01, 0x00]), Expr::Bin(BinOp::Sra, Box::new(shl), Box::new(Expr::Const(56))));
+        // sh a0, 0(sp); lhu a2, 0(sp)
+        assert_eq!(forwarded(&[0x23, 0x10, 0xa1, 0x00, 0x03, 0x56, 0x01, 0x00]), Expr::Bin(BinOp::And, a0(), Box::new(Expr::Const(0xffff))));
+
+        // A full register on RV32
+        let mut emu = Emulator::new(Arch::RV32);
+        run(&mut emu, &[0x2a, 0xc0, 0x02, 0x46]);
+        assert_eq!(emu.reg(reg(RISCV_REG_A2)).unwrap(), &Expr::Reg(reg(RISCV_REG_A0)));
+    }
+
+    #[test]
+    fn narrower_store_overrides_forwarding() {
+        // sd a0, 0(sp); sb a1, 1(sp); ld a2, 0(sp)
+        let code = [0x2a, 0xe0, 0xa3, 0x00, 0xb1, 0x00, 0x02, 0x66];
+        let mut emu = Emulator::new(Arch::RV64);
+        run(&mut emu, &code);
+        let a2 = emu.reg(reg(RISCV_REG_A2)).unwrap();
+        assert!(matches!(a2, Expr::Opaque(_)), "a2 = {}", a2);
+
+        let mut emu = Emulator::new(Arch::RV64);
+        emu.set_reg(reg(RISCV_REG_SP), 0x8000).unwrap();
+        emu.set_reg(reg(RISCV_REG_A0), 0x1122334455667788).unwrap();
+        emu.set_reg(reg(RISCV_REG_A1), 0xff).unwrap();
+        run(&mut emu, &code);
+        assert_eq!(emu.reg(reg(RISCV_REG_A2)).unwrap(), &Expr::Const(0x112233445566ff88));
+    }
+
+}
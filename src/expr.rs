@@ -0,0 +1,480 @@
+use std::fmt;
+
+use capstone::prelude::*;
+
+use crate::core::{self, Arch};
+use crate::err::RVError;
+use crate::gadget::{Gadget, GadgetInsn, Operand, RootKind};
+
+/// Comparison applied to the immediates of an `imm(..)` predicate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Cmp {
+    fn holds(&self, lhs: i64, rhs: i64) -> bool {
+        match self {
+            Cmp::Eq => lhs == rhs,
+            Cmp::Ne => lhs != rhs,
+            Cmp::Lt => lhs < rhs,
+            Cmp::Le => lhs <= rhs,
+            Cmp::Gt => lhs > rhs,
+            Cmp::Ge => lhs >= rhs,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Cmp::Eq => "==",
+            Cmp::Ne => "!=",
+            Cmp::Lt => "<",
+            Cmp::Le => "<=",
+            Cmp::Gt => ">",
+            Cmp::Ge => ">=",
+        }
+    }
+}
+
+/// Leaf of a query expression, true when any instruction of the gadget matches
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    Writes(RegId),
+    Reads(RegId),
+    Op(InsnId),
+    Imm(Cmp, i64),
+    /// Matches the root instruction only, against its mnemonic and, when
+    /// given, its operands. Indirect jumps also match as `jr <reg>` or
+    /// `jalr <reg>` whatever their encoding, returns being `jr ra`.
+    Ends(String, Option<String>),
+}
+
+impl Predicate {
+    pub fn is_satisfied_by_ins(&self, ins: &GadgetInsn) -> bool {
+        match self {
+            Predicate::Writes(reg) => ins.regs_written().contains(reg),
+            Predicate::Reads(reg) => ins.regs_read().contains(reg),
+            Predicate::Op(id) => ins.id() == *id,
+            Predicate::Imm(cmp, val) => ins.operands().iter().any(|op| match op {
+                Operand::Imm(imm) => cmp.holds(*imm, *val),
+                _ => false,
+            }),
+            Predicate::Ends(..) => false,
+        }
+    }
+
+    pub fn is_satisfied_by_gadget(&self, gadget: &Gadget) -> bool {
+        match self {
+            Predicate::Ends(mnemonic, ops) => {
+                let root = gadget.root();
+                let name = root.mnemonic().unwrap_or("");
+                let name = name.strip_prefix("c.").unwrap_or(name);
+                if name.eq_ignore_ascii_case(mnemonic) && ops.as_ref().is_none_or(|ops| normalize(root.op_str().unwrap_or("")) == *ops) {
+                    return true;
+                }
+                // Capstone prints `ret`, `jr a5` or `jalr a5` for both encodings
+                // and `jalr ra, 0(a5)` in some cases, the target register decides
+                let form = match gadget.kind() {
+                    RootKind::Return | RootKind::Jump => "jr",
+                    RootKind::Call => "jalr",
+                    RootKind::Syscall => return false,
+                };
+                let target = root.regs_read().last().map(|reg| core::reg_name(*reg));
+                mnemonic == form && ops.as_ref().is_none_or(|ops| target.as_ref() == Some(ops))
+            }
+            _ => gadget.insns().iter().any(|ins| self.is_satisfied_by_ins(ins)),
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Predicate::Writes(reg) => write!(f, "writes({})", core::reg_name(*reg)),
+            Predicate::Reads(reg) => write!(f, "reads({})", core::reg_name(*reg)),
+            Predicate::Op(id) => write!(f, "op({})", Arch::RV64.capstone().insn_name(*id).unwrap_or_default()),
+            Predicate::Imm(cmp, val) if *val < 0 => write!(f, "imm({}-{:#x})", cmp.symbol(), val.unsigned_abs()),
+            Predicate::Imm(cmp, val) => write!(f, "imm({}{:#x})", cmp.symbol(), val),
+            Predicate::Ends(mnemonic, None) => write!(f, "ends({})", mnemonic),
+            Predicate::Ends(mnemonic, Some(ops)) => write!(f, "ends({} {})", mnemonic, ops),
+        }
+    }
+}
+
+/// Boolean query over the instructions of a gadget, as given to `--query`
+///
+/// ```text
+/// expr := term ('or' term)*
+/// term := factor ('and' factor)*
+/// factor := 'not' factor | '(' expr ')' | predicate
+/// predicate := writes(reg) | reads(reg) | op(ins) | imm([cmp]int) | ends(ins [ops])
+/// ```
+///
+/// `&&`, `||` and `!` are accepted as well. Predicates hold when at least one
+/// instruction of the gadget matches, so `writes(a0) and reads(s1)` may be
+/// satisfied by two different instructions.
+#[derive(Clone, Debug)]
+pub enum QueryExpr {
+    Pred(Predicate),
+    Not(Box<QueryExpr>),
+    And(Box<QueryExpr>, Box<QueryExpr>),
+    Or(Box<QueryExpr>, Box<QueryExpr>),
+}
+
+impl QueryExpr {
+    pub fn is_satisfied_by_gadget(&self, gadget: &Gadget) -> bool {
+        match self {
+            QueryExpr::Pred(pred) => pred.is_satisfied_by_gadget(gadget),
+            QueryExpr::Not(e) => !e.is_satisfied_by_gadget(gadget),
+            QueryExpr::And(l, r) => l.is_satisfied_by_gadget(gadget) && r.is_satisfied_by_gadget(gadget),
+            QueryExpr::Or(l, r) => l.is_satisfied_by_gadget(gadget) || r.is_satisfied_by_gadget(gadget),
+        }
+    }
+
+    /// Whether the instruction matches a predicate that is not negated, which
+    /// is what gets highlighted in the output
+    pub fn highlights(&self, ins: &GadgetInsn) -> bool {
+        return self.highlights_with(ins, false);
+    }
+
+    fn highlights_with(&self, ins: &GadgetInsn, negated: bool) -> bool {
+        match self {
+            QueryExpr::Pred(pred) => !negated && pred.is_satisfied_by_ins(ins),
+            QueryExpr::Not(e) => e.highlights_with(ins, !negated),
+            QueryExpr::And(l, r) | QueryExpr::Or(l, r) => l.highlights_with(ins, negated) || r.highlights_with(ins, negated),
+        }
+    }
+}
+
+impl fmt::Display for QueryExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryExpr::Pred(pred) => write!(f, "{}", pred),
+            QueryExpr::Not(e) => write!(f, "not {}", e),
+            QueryExpr::And(l, r) => write!(f, "({} and {})", l, r),
+            QueryExpr::Or(l, r) => write!(f, "({} or {})", l, r),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    /// Predicate name with its raw argument
+    Call(String, String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::And => write!(f, "'and'"),
+            Token::Or => write!(f, "'or'"),
+            Token::Not => write!(f, "'not'"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::Call(name, arg) => write!(f, "'{}({})'", name, arg),
+        }
+    }
+}
+
+fn error(msg: String, col: usize) -> RVError {
+    return RVError {msg: format!("invalid query, {} at column {}", msg, col + 1)};
+}
+
+fn normalize(s: &str) -> String {
+    return s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+        .to_lowercase();
+}
+
+fn tokenize(src: &str) -> Result<Vec<(Token, usize)>, RVError> {
+    let chars: Vec<(usize, char)> = src.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let (col, c) = chars[i];
+        let next = chars.get(i + 1).map(|(_, c)| *c);
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => { tokens.push((Token::LParen, col)); i += 1; }
+            ')' => { tokens.push((Token::RParen, col)); i += 1; }
+            '!' => { tokens.push((Token::Not, col)); i += 1; }
+            '&' if next == Some('&') => { tokens.push((Token::And, col)); i += 2; }
+            '|' if next == Some('|') => { tokens.push((Token::Or, col)); i += 2; }
+            c if c.is_ascii_alphabetic() => {
+                let start = i;
+                while i < chars.len() && (chars[i].1.is_ascii_alphanumeric() || chars[i].1 == '_') {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().map(|(_, c)| c).collect();
+                match word.to_lowercase().as_str() {
+                    "and" => tokens.push((Token::And, col)),
+                    "or" => tokens.push((Token::Or, col)),
+                    "not" => tokens.push((Token::Not, col)),
+                    _ => {
+                        while i < chars.len() && chars[i].1.is_whitespace() {
+                            i += 1;
+                        }
+                        if i >= chars.len() || chars[i].1 != '(' {
+                            return Err(error(format!("expected '(' after '{}'", word), col));
+                        }
+                        // Arguments may hold parentheses, as in 'ends(jalr 0(a5))'
+                        let open = i;
+                        let mut depth = 0;
+                        while i < chars.len() {
+                            match chars[i].1 {
+                                '(' => depth += 1,
+                                ')' if depth == 1 => break,
+                                ')' => depth -= 1,
+                                _ => {}
+                            }
+                            i += 1;
+                        }
+                        if i >= chars.len() {
+                            return Err(error(String::from("unclosed '('"), chars[open].0));
+                        }
+                        let arg: String = chars[open + 1..i].iter().map(|(_, c)| c).collect();
+                        tokens.push((Token::Call(word.to_lowercase(), arg.trim().to_string()), col));
+                        i += 1;
+                    }
+                }
+            }
+            c => return Err(error(format!("unexpected '{}'", c), col)),
+        }
+    }
+    return Ok(tokens);
+}
+
+fn predicate(name: &str, arg: &str, col: usize) -> Result<Predicate, RVError> {
+    let wrap = |e: RVError| error(format!("{} in '{}({})'", e.msg, name, arg), col);
+    match name {
+        "writes" => Ok(Predicate::Writes(core::reg_from_str(arg).map_err(wrap)?)),
+        "reads" => Ok(Predicate::Reads(core::reg_from_str(arg).map_err(wrap)?)),
+        "op" => Ok(Predicate::Op(core::ins_from_str(arg).map_err(wrap)?)),
+        "imm" => {
+            let (cmp, val) = [(">=", Cmp::Ge), ("<=", Cmp::Le), ("==", Cmp::Eq), ("!=", Cmp::Ne), (">", Cmp::Gt), ("<", Cmp::Lt), ("=", Cmp::Eq)]
+                .iter()
+                .find_map(|(sym, cmp)| arg.strip_prefix(sym).map(|val| (*cmp, val.trim())))
+                .unwrap_or((Cmp::Eq, arg));
+            let (neg, val) = match val.strip_prefix('-') {
+                Some(val) => (true, val),
+                None => (false, val),
+            };
+            let val = core::int_from_str(val).map_err(wrap)? as i64;
+            Ok(Predicate::Imm(cmp, if neg { val.wrapping_neg() } else { val }))
+        }
+        "ends" => {
+            let (mnemonic, ops) = match arg.split_once(char::is_whitespace) {
+                Some((m, ops)) => (m, Some(normalize(ops))),
+                None => (arg, None),
+            };
+            if mnemonic.is_empty() {
+                return Err(error(String::from("missing instruction in 'ends()'"), col));
+            }
+            if mnemonic.eq_ignore_ascii_case("ret") && ops.is_none() {
+                return Ok(Predicate::Ends(String::from("jr"), Some(String::from("ra"))));
+            }
+            let mnemonic = mnemonic.to_lowercase();
+            let mnemonic = mnemonic.strip_prefix("c.").filter(|m| *m == "jr" || *m == "jalr").unwrap_or(&mnemonic);
+            Ok(Predicate::Ends(String::from(mnemonic), ops))
+        }
+        _ => Err(error(format!("unknown predicate '{}', expected writes, reads, op, imm or ends", name), col)),
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<&Token> {
+        return self.tokens.get(self.pos).map(|(t, _)| t);
+    }
+
+    fn col(&self) -> usize {
+        return self.tokens.get(self.pos).map_or(self.end, |(_, c)| *c);
+    }
+
+    fn expr(&mut self) -> Result<QueryExpr, RVError> {
+        let mut lhs = self.term()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            lhs = QueryExpr::Or(Box::new(lhs), Box::new(self.term()?));
+        }
+        return Ok(lhs);
+    }
+
+    fn term(&mut self) -> Result<QueryExpr, RVError> {
+        let mut lhs = self.factor()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            lhs = QueryExpr::And(Box::new(lhs), Box::new(self.factor()?));
+        }
+        return Ok(lhs);
+    }
+
+    fn factor(&mut self) -> Result<QueryExpr, RVError> {
+        let col = self.col();
+        let Some(token) = self.peek().cloned() else {
+            return Err(error(String::from("unexpected end of query"), col));
+        };
+        self.pos += 1;
+        match token {
+            Token::Not => Ok(QueryExpr::Not(Box::new(self.factor()?))),
+            Token::LParen => {
+                let inner = self.expr()?;
+                if self.peek() != Some(&Token::RParen) {
+                    return Err(error(String::from("expected ')'"), self.col()));
+                }
+                self.pos += 1;
+                Ok(inner)
+            }
+            Token::Call(name, arg) => Ok(QueryExpr::Pred(predicate(&name, &arg, col)?)),
+            t => Err(error(format!("unexpected {}", t), col)),
+        }
+    }
+
+}
+
+/// Parses a query expression, see [`QueryExpr`] for the grammar
+pub fn parse(src: &str) -> Result<QueryExpr, RVError> {
+    let tokens = tokenize(src)?;
+    let mut parser = Parser {tokens, pos: 0, end: src.len()};
+    let expr = parser.expr()?;
+    if let Some(token) = parser.peek() {
+        return Err(error(format!("unexpected {}", token), parser.col()));
+    }
+    return Ok(expr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gadget made of the whole of `code`
+    fn gadget(code: &[u8]) -> Gadget {
+        let cache = crate::cache::InsnCache::build(Arch::RV64, code, 0x1000);
+        return core::gadget_at(&cache, 0, 16).unwrap();
+    }
+
+    fn holds(src: &str, code: &[u8]) -> bool {
+        return parse(src).unwrap().is_satisfied_by_gadget(&gadget(code));
+    }
+
+    fn parsed(src: &str) -> String {
+        return parse(src).unwrap().to_string();
+    }
+
+    fn failure(src: &str) -> String {
+        return parse(src).unwrap_err().msg;
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(parsed("writes(a0) or reads(a1) and op(ld)"), "(writes(a0) or (reads(a1) and op(ld)))");
+        assert_eq!(parsed("writes(a0) and reads(a1) or op(ld)"), "((writes(a0) and reads(a1)) or op(ld))");
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        assert_eq!(parsed("not writes(sp) and reads(a1)"), "(not writes(sp) and reads(a1))");
+        assert_eq!(parsed("!!writes(sp)"), "not not writes(sp)");
+    }
+
+    #[test]
+    fn operators_are_left_associative() {
+        assert_eq!(parsed("op(ld) or op(lw) or op(lh)"), "((op(ld) or op(lw)) or op(lh))");
+        assert_eq!(parsed("op(ld) && op(lw) && op(lh)"), "((op(ld) and op(lw)) and op(lh))");
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        assert_eq!(parsed("(writes(a0) or reads(a1)) and op(ld)"), "((writes(a0) or reads(a1)) and op(ld))");
+        assert_eq!(parsed("not (writes(a0) || reads(a1))"), "not (writes(a0) or reads(a1))");
+        assert_eq!(parsed("((writes(a0)))"), "writes(a0)");
+    }
+
+    #[test]
+    fn predicate_arguments() {
+        assert_eq!(parsed("imm(>=-0x10)"), "imm(>=-0x10)");
+        assert_eq!(parsed("imm(8)"), "imm(==0x8)");
+        assert_eq!(parsed("IMM( != 4 )"), "imm(!=0x4)");
+        assert_eq!(parsed("ends(ret)"), "ends(jr ra)");
+        assert_eq!(parsed("ends(JALR ra, 0(a5))"), "ends(jalr ra,0(a5))");
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(failure(""), "invalid query, unexpected end of query at column 1");
+        assert_eq!(failure("writes(a0) and"), "invalid query, unexpected end of query at column 15");
+        assert_eq!(failure("(writes(a0)"), "invalid query, expected ')' at column 12");
+        assert_eq!(failure("writes(a0))"), "invalid query, unexpected ')' at column 11");
+        assert_eq!(failure("writes a0"), "invalid query, expected '(' after 'writes' at column 1");
+        assert_eq!(failure("reads(a0"), "invalid query, unclosed '(' at column 6");
+        assert_eq!(failure("op(ld) # op(lw)"), "invalid query, unexpected '#' at column 8");
+        assert_eq!(failure("loads(a0)"), "invalid query, unknown predicate 'loads', expected writes, reads, op, imm or ends at column 1");
+        assert_eq!(failure("ends()"), "invalid query, missing instruction in 'ends()' at column 1");
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert!(failure("writes(q9)").contains("in 'writes(q9)'"));
+        assert!(failure("op(foo)").contains("in 'op(foo)'"));
+        assert!(failure("imm(>x)").contains("in 'imm(>x)'"));
+        assert!(failure("op(ld) and or op(lw)").starts_with("invalid query, unexpected 'or'"));
+        assert!(failure("writes(a0) reads(a1)").starts_with("invalid query, unexpected 'reads(a1)'"));
+    }
+
+    #[test]
+    fn ends_matches_every_return_encoding() {
+        // li a0, 5, then c.jr ra or ret (jalr zero, 0(ra))
+        for code in [&[0x15, 0x45, 0x82, 0x80][..], &[0x15, 0x45, 0x67, 0x80, 0x00, 0x00]] {
+            for src in ["ends(ret)", "ends(jr ra)", "ends(jr)", "ends(c.jr ra)", "ends(JR RA)"] {
+                assert!(holds(src, code), "{} on {:02x?}", src, code);
+            }
+            assert!(!holds("ends(jr a5)", code));
+            assert!(!holds("ends(jalr)", code));
+        }
+    }
+
+    #[test]
+    fn ends_matches_jumps_by_target() {
+        // li a0, 5, then c.jr a5 or jalr zero, 0(a5)
+        for code in [&[0x15, 0x45, 0x82, 0x87][..], &[0x15, 0x45, 0x67, 0x80, 0x07, 0x00]] {
+            assert!(holds("ends(jr a5)", code));
+            assert!(holds("ends(c.jr)", code));
+            assert!(!holds("ends(ret)", code));
+            assert!(!holds("ends(jr a4)", code));
+        }
+    }
+
+    #[test]
+    fn ends_matches_calls_by_target() {
+        // li a0, 5, then c.jalr a5, jalr ra, 0(a5) or jalr t0, 8(a5)
+        for code in [&[0x15, 0x45, 0x82, 0x97][..], &[0x15, 0x45, 0xe7, 0x80, 0x07, 0x00]] {
+            assert!(holds("ends(jalr a5)", code));
+            assert!(holds("ends(c.jalr a5)", code));
+            assert!(!holds("ends(jr)", code));
+        }
+        let code = [0x15, 0x45, 0xe7, 0x82, 0x87, 0x00];
+        assert!(holds("ends(jalr t0, a5, 8)", &code));
+        assert!(holds("ends(jalr a5)", &code));
+        assert!(!holds("ends(jalr a4)", &code));
+    }
+
+}
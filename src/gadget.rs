@@ -1,47 +1,79 @@
-use std::fmt;
-use crate::core::{is_arithmetic, is_load};
-use std::ops::Deref;
+use std::{fmt, iter};
+use crate::core::{Arch, is_arithmetic, is_load, is_store, is_cond_branch, is_rmw, reg_name, ECALL_READS};
 use std::hash::{Hash, Hasher};
-use std::collections::HashMap;
 
 use capstone::{
     Insn,
     InsnId,
     RegId,
-    OwnedInsn,
     Capstone,
     arch::riscv::RiscVOperand,
-    arch::riscv::RiscVOperand::*,
+    arch::riscv::{RiscVInsn, RiscVInsn::*, RiscVReg::*},
     arch::DetailsArchInsn,
 };
 use colored::*;
+use serde_json::{json, Value};
 
+use crate::emu::{BinOp, Effects, Emulator, Expr};
 use crate::err::RVError;
 use crate::query::Query;
+use crate::symbols::Symbols;
+use crate::syscalls;
+use Operand::*;
 
-#[derive (Clone, Copy)]
+#[derive (Clone, Copy, Debug)]
 pub enum OutputMode {
     Inline,
     Block,
+    Json,
+    JsonLines,
+    Python,
 }
 
-pub struct GadgetInsn<'a> {
-    ins: OwnedInsn<'a>,
-    ops: Vec<RiscVOperand>
+pub fn outmode_from_str(mode: &str) -> Result<OutputMode, RVError> {
+    match mode {
+        "inline" => Ok(OutputMode::Inline),
+        "block" => Ok(OutputMode::Block),
+        "json" => Ok(OutputMode::Json),
+        "jsonl" => Ok(OutputMode::JsonLines),
+        "python" => Ok(OutputMode::Python),
+        _ => Err(RVError {msg: String::from("not an output format (block, inline, json, jsonl, python)")}),
+    }
 }
 
-impl<'a> Clone for GadgetInsn<'a> {
+/// Operand of a decoded instruction
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Reg(RegId),
+    Imm(i64),
+    /// Memory at a base register plus a displacement
+    Mem(RegId, i64),
+    Invalid,
+}
 
-    fn clone(&self) -> Self {
-        return GadgetInsn {
-            ins: OwnedInsn::from(self.ins.deref()),
-            ops: self.ops.clone()
+impl From<RiscVOperand> for Operand {
+    fn from(op: RiscVOperand) -> Self {
+        match op {
+            RiscVOperand::Reg(reg) => Reg(reg),
+            RiscVOperand::Imm(imm) => Imm(imm),
+            RiscVOperand::Mem(mem) => Mem(mem.base(), mem.disp()),
+            RiscVOperand::Invalid => Invalid,
         }
     }
+}
 
+/// Decoded instruction, detached from the Capstone handle that produced it
+#[derive (Clone, Debug)]
+pub struct GadgetInsn {
+    id: InsnId,
+    address: u64,
+    bytes: Vec<u8>,
+    mnemonic: String,
+    op_str: String,
+    ops: Vec<Operand>
 }
 
-impl fmt::Display for GadgetInsn<'_> {
+impl fmt::Display for GadgetInsn {
 
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut mnemonic = self.mnemonic().unwrap();
@@ -53,14 +85,18 @@ impl fmt::Display for GadgetInsn<'_> {
 
 }
 
-impl<'a> GadgetInsn<'a> {
+impl GadgetInsn {
 
-    pub fn create(cs: &'a Capstone, ins: &Insn) -> Result<Self, RVError> {
+    pub fn create(cs: &Capstone, ins: &Insn) -> Result<Self, RVError> {
         if let Ok(details) = cs.insn_detail(ins) {
             if let Some(arch) = details.arch_detail().riscv() {
                 let g = GadgetInsn {
-                    ins: OwnedInsn::from(ins),
-                    ops: arch.operands().collect(),
+                    id: ins.id(),
+                    address: ins.address(),
+                    bytes: ins.bytes().to_vec(),
+                    mnemonic: String::from(ins.mnemonic().unwrap_or("")),
+                    op_str: String::from(ins.op_str().unwrap_or("")),
+                    ops: arch.operands().map(Operand::from).collect(),
                 };
                 return Ok(g);
             }
@@ -70,27 +106,68 @@ impl<'a> GadgetInsn<'a> {
     
     }
 
+    /// Self-contained representation stored in gadget indexes
+    pub fn to_record(&self) -> Value {
+        let ops: Vec<Value> = self.ops.iter().map(|op| match op {
+            Reg(reg) => json!(["reg", reg.0]),
+            Imm(imm) => json!(["imm", imm]),
+            Mem(base, disp) => json!(["mem", base.0, disp]),
+            Invalid => json!(["invalid"]),
+        }).collect();
+        json!({
+            "id": self.id.0,
+            "address": self.address,
+            "bytes": self.bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "mnemonic": self.mnemonic,
+            "op_str": self.op_str,
+            "ops": ops,
+        })
+    }
+
+    /// Rebuilds an instruction stored by `to_record`, without decoding it again
+    pub fn from_record(record: &Value) -> Result<Self, RVError> {
+        let bad = || RVError {msg: String::from("corrupted instruction record")};
+        let ops = record["ops"].as_array().ok_or_else(bad)?.iter().map(|op| {
+            let arg = |i: usize| op.get(i).and_then(Value::as_i64).ok_or_else(bad);
+            return match op.get(0).and_then(Value::as_str) {
+                Some("reg") => Ok(Reg(RegId(arg(1)? as u16))),
+                Some("imm") => Ok(Imm(arg(1)?)),
+                Some("mem") => Ok(Mem(RegId(arg(1)? as u16), arg(2)?)),
+                Some("invalid") => Ok(Invalid),
+                _ => Err(bad()),
+            };
+        }).collect::<Result<Vec<_>, RVError>>()?;
+        return Ok(GadgetInsn {
+            id: InsnId(record["id"].as_u64().ok_or_else(bad)? as u32),
+            address: record["address"].as_u64().ok_or_else(bad)?,
+            bytes: crate::core::hex_decode(record["bytes"].as_str().ok_or_else(bad)?)?,
+            mnemonic: String::from(record["mnemonic"].as_str().ok_or_else(bad)?),
+            op_str: String::from(record["op_str"].as_str().ok_or_else(bad)?),
+            ops,
+        });
+    }
+
     pub fn bytes(&self) -> &[u8] {
-        return self.ins.bytes();
+        return &self.bytes;
     }
 
     pub fn id(&self) -> InsnId {
-        return self.ins.id();
+        return self.id;
     }
 
     pub fn mnemonic(&self) -> Option<&str> {
-        return self.ins.mnemonic();
+        return Some(&self.mnemonic);
     }
 
     pub fn op_str(&self) -> Option<&str> {
-        return self.ins.op_str();
+        return Some(&self.op_str);
     }
 
     pub fn address(&self) -> u64 {
-        return self.ins.address();
+        return self.address;
     }
 
-    pub fn operands(&self) -> &Vec<RiscVOperand> {
+    pub fn operands(&self) -> &Vec<Operand> {
         return &self.ops;
     }
 
@@ -101,12 +178,94 @@ impl<'a> GadgetInsn<'a> {
         })
     }
 
+    /// Base register and displacement of a load or store memory operand
+    pub fn mem_operand(&self) -> Option<(RegId, i64)> {
+        if !is_load(self.id()) && !is_store(self.id()) {
+            return None;
+        }
+        // Compressed forms are decoded as `reg, imm, base` instead of a memory operand
+        match self.operands().as_slice() {
+            [_, Mem(base, disp)] => Some((*base, *disp)),
+            [_, Imm(disp), Reg(base)] => Some((*base, *disp)),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction stores to memory, atomics included
+    pub fn writes_memory(&self) -> bool {
+        return is_store(self.id()) || self.mnemonic.starts_with("amo") || self.mnemonic.starts_with("sc.");
+    }
+
+    /// Registers defined by the instruction, `zero` excluded
+    pub fn regs_written(&self) -> Vec<RegId> {
+        return self.access().0;
+    }
+
+    /// Registers used by the instruction, including memory operand bases
+    pub fn regs_read(&self) -> Vec<RegId> {
+        return self.access().1;
+    }
+
+    fn access(&self) -> (Vec<RegId>, Vec<RegId>) {
+        let ra = RegId(RISCV_REG_RA as u16);
+        let id = RiscVInsn::from(self.id().0);
+        let mut regs: Vec<RegId> = self.regs().collect();
+
+        // Jump aliases (ret, jr, j, jal <off>, jalr <rs>) hide the link register
+        let (mut written, mut read) = match id {
+            RISCV_INS_JAL => match (regs.is_empty(), self.mnemonic()) {
+                (true, Some("jal")) => (vec![ra], vec![]),
+                (true, _) => (vec![], vec![]),
+                (false, _) => (vec![regs[0]], vec![]),
+            },
+            RISCV_INS_JALR => match (regs.len(), self.mnemonic()) {
+                (0, _) => (vec![], vec![ra]),
+                (1, Some("jr")) => (vec![], regs),
+                (1, _) => (vec![ra], regs),
+                _ => (vec![regs.remove(0)], regs),
+            },
+            RISCV_INS_C_JAL => (vec![ra], vec![]),
+            RISCV_INS_C_JR => (vec![], regs),
+            RISCV_INS_C_JALR => (vec![ra], regs),
+            RISCV_INS_ECALL => (
+                vec![RegId(RISCV_REG_A0 as u16)],
+                ECALL_READS.iter().map(|&r| RegId(r as u16)).collect(),
+            ),
+            _ if is_store(self.id()) || is_cond_branch(self.id()) || regs.is_empty() => (vec![], regs),
+            _ if is_rmw(self.id()) => (vec![regs[0]], regs),
+            _ => (vec![regs.remove(0)], regs),
+        };
+        read.extend(self.operands().iter().filter_map(|op| match op {
+            Mem(base, _) => Some(*base),
+            _ => None,
+        }));
+
+        let zero = RegId(RISCV_REG_ZERO as u16);
+        written.retain(|&r| r != zero);
+        read.retain(|&r| r != zero);
+        return (written, read);
+    }
+
     pub fn satisfies(&self, q: &Query) -> bool {
         return q.is_satisfied_by_ins(self);
     }
 
-    pub fn print(&self, q: &Query, last: bool) {
-        let addr = format!("{:#010x}", self.address());
+    pub fn to_json(&self, q: &Query) -> Value {
+        let operands: Vec<&str> = match self.op_str() {
+            Some(ops) if !ops.is_empty() => ops.split(", ").collect(),
+            _ => Vec::new(),
+        };
+        json!({
+            "address": self.address(),
+            "bytes": self.bytes().iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "mnemonic": self.mnemonic().unwrap_or(""),
+            "operands": operands,
+            "matched": self.satisfies(q),
+        })
+    }
+
+    pub fn print(&self, q: &Query, last: bool, arch: Arch) {
+        let addr = format!("{:#0w$x}", self.address(), w = arch.addr_width() + 2);
         let bytes = self.bytes().iter().fold(String::new(), |mut acc, b| {
             acc.push_str(&format!("{:02x} ", b));
             acc
@@ -130,29 +289,287 @@ impl<'a> GadgetInsn<'a> {
 
 }
 
-#[derive(Clone)]
-pub struct GadgetRoot<'a> {
-    pub root: GadgetInsn<'a>,
+/// Effect of a gadget on the stack. Offsets are relative to `sp` at the gadget entry
+#[derive (Clone, Debug, Default, PartialEq, Eq)]
+pub struct StackEffect {
+    /// Total `sp` adjustment, `None` when `sp` is set to a non constant value
+    pub sp_delta: Option<i64>,
+    /// Registers loaded from the stack, with their slot offset
+    pub loads: Vec<(i64, RegId)>,
+    /// Registers stored to the stack, with their slot offset
+    pub stores: Vec<(i64, RegId)>,
+}
+
+impl StackEffect {
+
+    pub fn is_empty(&self) -> bool {
+        return self.sp_delta == Some(0) && self.loads.is_empty() && self.stores.is_empty();
+    }
+
+    pub fn to_json(&self) -> Value {
+        let slots = |slots: &Vec<(i64, RegId)>| -> Vec<Value> {
+            slots.iter().map(|(off, reg)| json!({"offset": off, "reg": reg_name(*reg)})).collect()
+        };
+        json!({
+            "sp_delta": self.sp_delta,
+            "loads": slots(&self.loads),
+            "stores": slots(&self.stores),
+        })
+    }
+
+}
+
+impl fmt::Display for StackEffect {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.sp_delta {
+            Some(delta) => write!(f, "sp {:+}", delta)?,
+            None => write!(f, "sp ?")?,
+        }
+        for (off, reg) in &self.loads {
+            write!(f, ", {} <- [sp{:+}]", reg_name(*reg), off)?;
+        }
+        for (off, reg) in &self.stores {
+            write!(f, ", [sp{:+}] <- {}", off, reg_name(*reg))?;
+        }
+        Ok(())
+    }
+
+}
+
+/// Control flow transfer ending a gadget
+#[derive (Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RootKind {
+    /// Jump through `ra` without linking (`ret`, `c.jr ra`)
+    Return,
+    /// Register-indirect jump without linking (`jr a5`, `c.jr a5`)
+    Jump,
+    /// Register-indirect call (`jalr ra, rs`, `c.jalr rs`)
+    Call,
+    /// System call (`ecall`)
+    Syscall,
+}
+
+impl RootKind {
+
+    /// Kind of an indirect branch or system call, `None` for any other instruction
+    pub fn of(ins: &GadgetInsn) -> Option<Self> {
+        let id = RiscVInsn::from(ins.id().0);
+        if id == RISCV_INS_ECALL {
+            return Some(RootKind::Syscall);
+        }
+        if id != RISCV_INS_JALR && id != RISCV_INS_C_JR && id != RISCV_INS_C_JALR {
+            return None;
+        }
+        let read = ins.regs_read();
+        if read.is_empty() {
+            return None;
+        }
+        if !ins.regs_written().is_empty() {
+            return Some(RootKind::Call);
+        }
+        if read == [RegId(RISCV_REG_RA as u16)] {
+            return Some(RootKind::Return);
+        }
+        return Some(RootKind::Jump);
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RootKind::Return => "rop",
+            RootKind::Jump => "jop",
+            RootKind::Call => "call",
+            RootKind::Syscall => "syscall",
+        }
+    }
+
+}
+
+/// Gadget kinds selected with the `--kind` option
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KindFilter {
+    Rop,
+    Jop,
+    Call,
+    /// Branching kinds only, system calls are searched on demand
+    All,
+    Syscall,
+}
+
+impl KindFilter {
+
+    pub fn accepts(&self, kind: RootKind) -> bool {
+        match self {
+            KindFilter::Rop => kind == RootKind::Return,
+            KindFilter::Jop => kind == RootKind::Jump,
+            KindFilter::Call => kind == RootKind::Call,
+            KindFilter::All => kind != RootKind::Syscall,
+            KindFilter::Syscall => kind == RootKind::Syscall,
+        }
+    }
+
+}
+
+pub fn kind_from_str(kind: &str) -> Result<KindFilter, RVError> {
+    match kind {
+        "rop" => Ok(KindFilter::Rop),
+        "jop" => Ok(KindFilter::Jop),
+        "call" => Ok(KindFilter::Call),
+        "all" => Ok(KindFilter::All),
+        "syscall" => Ok(KindFilter::Syscall),
+        _ => Err(RVError {msg: String::from("not a gadget kind (rop, jop, call, all, syscall)")}),
+    }
+}
+
+/// Functional category of a gadget when assembling a JOP chain
+#[derive (Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum GadgetClass {
+    /// Loads the jump target from a table and advances the table index
+    Dispatcher,
+    /// Loads at least two registers from memory, setting up the chain state
+    Initializer,
+    /// Moves `sp` to an address that is not a constant offset from its entry value
+    Pivot,
+    /// Sets a register to a constant
+    LoadConst,
+    /// Copies a register to another one
+    Move,
+    /// Computes a register from other registers
+    Arithmetic,
+    Load,
+    Store,
+    /// Performs a system call or sets its number in `a7`
+    Syscall,
+}
+
+impl GadgetClass {
+
+    pub const ALL: [GadgetClass; 9] = [
+        GadgetClass::Dispatcher,
+        GadgetClass::Initializer,
+        GadgetClass::Pivot,
+        GadgetClass::LoadConst,
+        GadgetClass::Move,
+        GadgetClass::Arithmetic,
+        GadgetClass::Load,
+        GadgetClass::Store,
+        GadgetClass::Syscall,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GadgetClass::Dispatcher => "dispatcher",
+            GadgetClass::Initializer => "init",
+            GadgetClass::Pivot => "pivot",
+            GadgetClass::LoadConst => "const",
+            GadgetClass::Move => "mov",
+            GadgetClass::Arithmetic => "arith",
+            GadgetClass::Load => "load",
+            GadgetClass::Store => "store",
+            GadgetClass::Syscall => "syscall",
+        }
+    }
+
+}
+
+pub fn class_from_str(class: &str) -> Result<GadgetClass, RVError> {
+    return GadgetClass::ALL.iter()
+        .find(|c| c.name() == class)
+        .copied()
+        .ok_or(RVError {msg: String::from("not a gadget class (dispatcher, init, pivot, const, mov, arith, load, store, syscall)")});
+}
+
+/// Amount by which a dispatcher advances its dispatch register
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stride {
+    /// Constant, in bytes of the table
+    Imm(i64),
+    /// Value of a register
+    Reg(RegId),
+}
+
+impl fmt::Display for Stride {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stride::Imm(val) => write!(f, "{:+}", val),
+            Stride::Reg(reg) => write!(f, "+{}", reg_name(*reg)),
+        }
+    }
+}
+
+/// Dispatcher loop step: the jump target is loaded from a table entry
+/// selected by `reg`, which is then advanced by `stride`
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dispatch {
+    pub reg: RegId,
+    pub stride: Stride,
+    /// Table base when `reg` is an index, `None` when it points in the table
+    pub table: Option<RegId>,
+}
+
+impl Dispatch {
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "reg": reg_name(self.reg),
+            "stride": self.stride.to_string(),
+            "table": self.table.map(reg_name),
+        })
+    }
+
+}
+
+impl fmt::Display for Dispatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "dispatch {}", reg_name(self.reg))?;
+        if let Some(table) = self.table {
+            write!(f, " in {}", reg_name(table))?;
+        }
+        write!(f, ", stride {}", self.stride)
+    }
+}
+
+/// Register and scale of an index expression (`reg`, `reg << n`, `reg * n`)
+fn scaled_reg(expr: &Expr) -> Option<(RegId, u64)> {
+    match expr.strip_sext() {
+        Expr::Bin(BinOp::Sll, lhs, rhs) => Some((lhs.offset_reg()?, 1u64.checked_shl(rhs.as_const()? as u32)?)),
+        Expr::Bin(BinOp::Mul, lhs, rhs) => match (lhs.as_const(), rhs.as_const()) {
+            (None, Some(n)) => Some((lhs.offset_reg()?, n)),
+            (Some(n), None) => Some((rhs.offset_reg()?, n)),
+            _ => None,
+        },
+        expr => Some((expr.offset_reg()?, 1)),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GadgetRoot {
+    pub root: GadgetInsn,
     pub off: u64,
+    pub kind: RootKind,
 }
 
-impl<'a> GadgetRoot<'a> {
+impl GadgetRoot {
 
-    pub fn from(root: GadgetInsn<'a>, at: u64) -> Self {
+    pub fn from(root: GadgetInsn, at: u64, kind: RootKind) -> Self {
         return GadgetRoot {
             root,
-            off: at
+            off: at,
+            kind,
         }
     }
 
 }
 
-pub struct Gadget<'a> {
-    root: GadgetRoot<'a>,
-    insns: Vec<GadgetInsn<'a>>,
+#[derive(Clone, Debug)]
+pub struct Gadget {
+    root: GadgetRoot,
+    insns: Vec<GadgetInsn>,
+    arch: Arch,
+    symbol: Option<String>,
 }
 
-impl<'a> Hash for Gadget<'a> {
+impl Hash for Gadget {
     fn hash<H: Hasher>(&self, state: &mut H) {
         for ins in &self.insns {
             ins.bytes().hash(state);
@@ -161,7 +578,7 @@ impl<'a> Hash for Gadget<'a> {
     }
 }
 
-impl<'a> PartialEq for Gadget<'a> {
+impl PartialEq for Gadget {
     fn eq(&self, other: &Gadget) -> bool {
         if self.insns.len() != other.insns.len() {
             return false;
@@ -175,72 +592,363 @@ impl<'a> PartialEq for Gadget<'a> {
     }
 }
 
-impl<'a> Eq for Gadget<'a> {
+impl Eq for Gadget {
 
 }
 
-impl<'a> Gadget<'a> {
+impl Gadget {
 
-    pub fn create(root: GadgetRoot<'a>, insns: Vec<GadgetInsn<'a>>) -> Result<Self, RVError> {
+    pub fn create(root: GadgetRoot, insns: Vec<GadgetInsn>, arch: Arch) -> Result<Self, RVError> {
         let g = Gadget {
             root,
             insns,
+            arch,
+            symbol: None,
         };
 
         return Ok(g);
     }
 
+    /// Symbolic effects of the gadget, from its emulation
+    pub fn effects(&self) -> Option<Effects> {
+        return Emulator::new(self.arch).run(self).ok();
+    }
+
+    /// Dispatcher loop step performed by the gadget, if any. The gadget is
+    /// emulated and its jump target must be loaded from an address computed
+    /// from a register that the gadget also advances. Returns and jumps
+    /// through a table walked by `sp` are function epilogues, not dispatchers.
+    pub fn dispatch(&self) -> Option<Dispatch> {
+        return self.dispatch_in(&self.effects()?);
+    }
+
+    /// Dispatcher loop step found in the `effects` of the gadget
+    fn dispatch_in(&self, effects: &Effects) -> Option<Dispatch> {
+        if self.kind() == RootKind::Return {
+            return None;
+        }
+        let (target, _) = effects.target.as_ref()?.split_offset();
+        let Expr::Load(addr, _, _) = target else {
+            return None;
+        };
+        let (addr, _) = addr.split_offset();
+
+        // Either a pointer walking the table or an index scaled into a table base
+        let candidates = match addr {
+            Expr::Bin(BinOp::Add, lhs, rhs) => vec![
+                (scaled_reg(lhs), rhs.offset_reg()),
+                (scaled_reg(rhs), lhs.offset_reg()),
+            ],
+            _ => vec![(addr.offset_reg().map(|reg| (reg, 1)), None)],
+        };
+        for (index, table) in candidates {
+            let Some((reg, scale)) = index else {
+                continue;
+            };
+            if table == Some(reg) || reg == RegId(RISCV_REG_SP as u16) {
+                continue;
+            }
+            let Some((_, next)) = effects.regs.iter().find(|(r, _)| *r == reg) else {
+                continue;
+            };
+            let stride = match next.strip_sext() {
+                Expr::Bin(BinOp::Add, lhs, rhs) => match (lhs.as_ref(), rhs.as_ref()) {
+                    (Expr::Reg(r), Expr::Const(c)) if *r == reg => Stride::Imm(self.arch.signed(*c).wrapping_mul(scale as i64)),
+                    (Expr::Reg(r), Expr::Reg(by)) | (Expr::Reg(by), Expr::Reg(r)) if *r == reg && *by != reg => Stride::Reg(*by),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            return Some(Dispatch {reg, stride, table});
+        }
+        return None;
+    }
+
     pub fn is_dispatcher(&self) -> bool {
-        #[derive(Clone, Copy, Default)]
-        struct State { load: bool, add: bool }
+        return self.dispatch().is_some();
+    }
 
-        let mut states: HashMap<RegId, State> = HashMap::new();
-        let jrid = self.root.root.regs().last().unwrap();
+    /// Tracks `sp` through the gadget, recording stack slots accessed relative to its entry value
+    pub fn stack_effect(&self) -> StackEffect {
+        let sp = RegId(RISCV_REG_SP as u16);
+        let mut effect = StackEffect { sp_delta: Some(0), ..Default::default() };
 
         for ins in &self.insns {
-            if is_load(ins.id()) {
-                let src = ins.regs().last().unwrap();
-                let dst = ins.regs().next().unwrap();
-                if dst == jrid {
-                    states.entry(src).or_default().load = true;
-                }
-            } else if is_arithmetic(ins.id()) {
-                if let Some(dst) = ins.regs().next() {
-                    states.entry(dst).or_default().add = true;
+            if let (Some(delta), Some((base, disp))) = (effect.sp_delta, ins.mem_operand()) {
+                if base == sp {
+                    if let Some(reg) = ins.regs().next() {
+                        if is_load(ins.id()) {
+                            effect.loads.push((delta + disp, reg));
+                        } else {
+                            effect.stores.push((delta + disp, reg));
+                        }
+                    }
                 }
             }
+            if ins.regs_written().contains(&sp) {
+                let imm = ins.operands().iter().find_map(|op| match op {
+                    Imm(imm) => Some(*imm),
+                    _ => None,
+                });
+                effect.sp_delta = match (effect.sp_delta, imm) {
+                    (Some(delta), Some(imm)) if is_arithmetic(ins.id()) && ins.regs_read() == [sp] => Some(delta + imm),
+                    _ => None,
+                };
+            }
+        }
+        return effect;
+    }
+
+    /// Value of `a7` when a system call gadget reaches its `ecall`, when it
+    /// does not depend on the initial state
+    pub fn syscall(&self) -> Option<u64> {
+        if self.kind() != RootKind::Syscall {
+            return None;
+        }
+        return self.syscall_in(&self.effects()?);
+    }
+
+    /// System call number found in the `effects` of the gadget, the `ecall`
+    /// root leaving `a7` untouched
+    fn syscall_in(&self, effects: &Effects) -> Option<u64> {
+        if self.kind() != RootKind::Syscall {
+            return None;
+        }
+        let a7 = RegId(RISCV_REG_A7 as u16);
+        return effects.regs.iter().find(|(r, _)| *r == a7)?.1.as_const();
+    }
+
+    /// Dispatch and system call number of the gadget, from a single emulation
+    fn analysis(&self) -> (Option<Dispatch>, Option<u64>) {
+        let Some(effects) = self.effects() else {
+            return (None, None);
+        };
+        return (self.dispatch_in(&effects), self.syscall_in(&effects));
+    }
+
+    /// Returns the gadget if its encoded address contains none of the `bad`
+    /// bytes. With `later`, the first entry point past its start that avoids
+    /// them is used instead, down to the root alone. A later entry point has no
+    /// symbol, see [`Gadget::symbolize`].
+    pub fn avoiding(&self, bad: &[u8], arch: Arch, later: bool) -> Option<Gadget> {
+        if bad.is_empty() {
+            return Some(self.clone());
+        }
+        let entries = if later { self.insns.len() + 1 } else { 1 };
+        for skip in 0..entries {
+            let addr = self.insns.get(skip).unwrap_or(&self.root.root).address();
+            if !arch.encode_addr(addr).iter().any(|b| bad.contains(b)) {
+                return Some(self.entry_at(skip));
+            }
+        }
+        return None;
+    }
+
+    /// Location of the gadget as `func+0xoff`, when known
+    pub fn symbol(&self) -> Option<&str> {
+        return self.symbol.as_deref();
+    }
+
+    pub fn set_symbol(&mut self, symbol: Option<String>) {
+        self.symbol = symbol;
+    }
+
+    /// Names the gadget after the function containing its start. Gadgets
+    /// trimmed by `avoiding` or `truncated` are symbolized again this way.
+    pub fn symbolize(&mut self, symbols: &Symbols) {
+        self.symbol = symbols.symbolize(self.address());
+    }
+
+    /// Functional categories of the gadget, from its instructions before the root
+    pub fn classes(&self) -> Vec<GadgetClass> {
+        return self.classes_with(self.dispatch().as_ref());
+    }
+
+    /// Classes of the gadget, given its already computed `dispatch`
+    fn classes_with(&self, dispatch: Option<&Dispatch>) -> Vec<GadgetClass> {
+        let sp = RegId(RISCV_REG_SP as u16);
+        let a7 = RegId(RISCV_REG_A7 as u16);
+        let mut classes = Vec::new();
+        let mut loaded = Vec::new();
+
+        if dispatch.is_some() {
+            classes.push(GadgetClass::Dispatcher);
+        }
+        if self.kind() == RootKind::Syscall {
+            classes.push(GadgetClass::Syscall);
+        }
+        for ins in &self.insns {
+            let (written, read) = (ins.regs_written(), ins.regs_read());
+            let class = if RiscVInsn::from(ins.id().0) == RISCV_INS_ECALL || written.contains(&a7) {
+                Some(GadgetClass::Syscall)
+            } else if ins.writes_memory() {
+                Some(GadgetClass::Store)
+            } else if written.is_empty() || written.contains(&sp) {
+                None
+            } else if is_load(ins.id()) {
+                loaded.extend(written.iter().copied());
+                Some(GadgetClass::Load)
+            } else if read.is_empty() {
+                Some(GadgetClass::LoadConst)
+            } else if ins.mnemonic().is_some_and(|m| m.trim_start_matches("c.") == "mv") {
+                Some(GadgetClass::Move)
+            } else {
+                Some(GadgetClass::Arithmetic)
+            };
+            classes.extend(class);
+        }
+        loaded.sort_by_key(|r| r.0);
+        loaded.dedup();
+        if loaded.len() >= 2 {
+            classes.push(GadgetClass::Initializer);
         }
-        return states.values().any(|s| s.add && s.load);
+        if self.insns.iter().any(|ins| ins.regs_written().contains(&sp)) && self.stack_effect().sp_delta.is_none() {
+            classes.push(GadgetClass::Pivot);
+        }
+        classes.sort();
+        classes.dedup();
+        return classes;
     }
 
     pub fn insns(&self) -> &Vec<GadgetInsn> {
         return &self.insns;
     }
 
+    pub fn root(&self) -> &GadgetInsn {
+        return &self.root.root;
+    }
+
+    pub fn kind(&self) -> RootKind {
+        return self.root.kind;
+    }
+
     pub fn satisfies(&self, q: &Query) -> bool {
         return q.is_satisfied_by_gadget(self);
     }
 
+    pub fn address(&self) -> u64 {
+        return self.insns.first().unwrap_or(&self.root.root).address();
+    }
+
+    /// Self-contained representation stored in gadget indexes
+    pub fn to_record(&self) -> Value {
+        json!({
+            "kind": self.kind().name(),
+            "off": self.root.off,
+            "root": self.root.root.to_record(),
+            "insns": self.insns.iter().map(|ins| ins.to_record()).collect::<Vec<Value>>(),
+            "symbol": self.symbol(),
+        })
+    }
+
+    /// Rebuilds a gadget stored by `to_record`
+    pub fn from_record(record: &Value, arch: Arch) -> Result<Self, RVError> {
+        let bad = || RVError {msg: String::from("corrupted gadget record")};
+        let kind = match record["kind"].as_str() {
+            Some("rop") => RootKind::Return,
+            Some("jop") => RootKind::Jump,
+            Some("call") => RootKind::Call,
+            Some("syscall") => RootKind::Syscall,
+            _ => return Err(bad()),
+        };
+        let root = GadgetRoot::from(GadgetInsn::from_record(&record["root"])?, record["off"].as_u64().ok_or_else(bad)?, kind);
+        let insns = record["insns"].as_array().ok_or_else(bad)?.iter()
+            .map(GadgetInsn::from_record)
+            .collect::<Result<Vec<_>, RVError>>()?;
+        let mut gadget = Gadget::create(root, insns, arch)?;
+        gadget.set_symbol(record["symbol"].as_str().map(String::from));
+        return Ok(gadget);
+    }
+
+    /// The gadget restricted to its last `max` instructions before the root,
+    /// as found by a search with a lower maximum. It has no symbol when
+    /// instructions are dropped.
+    pub fn truncated(&self, max: usize) -> Gadget {
+        let skip = self.insns.len().saturating_sub(max);
+        return self.entry_at(skip);
+    }
+
+    /// The gadget starting `skip` instructions later. Its symbol only carries
+    /// over when the start does not move, as it holds the offset of the start.
+    fn entry_at(&self, skip: usize) -> Gadget {
+        let symbol = if skip == 0 { self.symbol.clone() } else { None };
+        return Gadget {root: self.root.clone(), insns: self.insns[skip..].to_vec(), arch: self.arch, symbol};
+    }
+
+    /// Instructions of the gadget on one line, e.g. `mv a0, s0; jr a5`
+    pub fn text(&self) -> String {
+        return self.insns.iter().chain(iter::once(&self.root.root))
+            .map(|ins| ins.to_string().trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+    }
+
+    pub fn to_json(&self, q: &Query) -> Value {
+        let (dispatch, syscall) = self.analysis();
+        json!({
+            "address": self.address(),
+            "symbol": self.symbol(),
+            "root": self.root.root.to_json(q),
+            "kind": self.kind().name(),
+            "stack": self.stack_effect().to_json(),
+            "classes": self.classes_with(dispatch.as_ref()).iter().map(|c| c.name()).collect::<Vec<_>>(),
+            "insns": self.insns.iter().map(|ins| ins.to_json(q)).collect::<Vec<Value>>(),
+            "dispatcher": dispatch.is_some(),
+            "dispatch": dispatch.map(|d| d.to_json()),
+            "syscall": syscall.map(|nr| json!({"number": nr, "name": syscalls::name(nr, self.arch)})),
+            "matched": self.satisfies(q),
+        })
+    }
+
     pub fn print(&self, q: &Query, mode: OutputMode) {
         match mode {
             OutputMode::Block => self.print_block(q),
             OutputMode::Inline => self.print_inline(q),
+            OutputMode::Json => println!("{:#}", self.to_json(q)),
+            OutputMode::JsonLines => println!("{}", self.to_json(q)),
+            OutputMode::Python => println!("gadgets['{}'] = {:#x}", self.text(), self.address()),
         };
     }
 
+    /// Classes, stack effect and dispatch of the gadget, as shown after its instructions
+    fn notes(&self) -> Option<String> {
+        let (dispatch, syscall) = self.analysis();
+        let classes: Vec<&str> = self.classes_with(dispatch.as_ref()).iter().map(|c| c.name()).collect();
+        let effect = self.stack_effect();
+        let mut notes = Vec::new();
+        if !classes.is_empty() {
+            notes.push(format!("[{}]", classes.join(", ")));
+        }
+        if !effect.is_empty() {
+            notes.push(format!("({})", effect));
+        }
+        if let Some(dispatch) = dispatch {
+            notes.push(format!("({})", dispatch));
+        }
+        if let Some(nr) = syscall {
+            notes.push(format!("(syscall {} {})", nr, syscalls::name(nr, self.arch).unwrap_or("unknown")));
+        }
+        if notes.is_empty() {
+            return None;
+        }
+        return Some(notes.join(" "));
+    }
+
     fn print_block(&self, q: &Query) {
+        if let Some(symbol) = self.symbol() {
+            println!("{}", format!("<{}>:", symbol).green());
+        }
         for ins in self.insns.iter() {
-            ins.print(q, false);
+            ins.print(q, false, self.arch);
+        }
+        self.root.root.print(q, true, self.arch);
+        if let Some(notes) = self.notes() {
+            println!("{:w$} {}", "", notes.dimmed(), w = self.arch.addr_width() + 2);
         }
-        self.root.root.print(q, true);
     }
 
     fn print_inline(&self, q: &Query) {
-        let addr = if let Some(a) = self.insns.first() {
-            format!("{:#010x}", a.address())
-        } else {
-            return;
-        };
+        let addr = format!("{:#0w$x}", self.address(), w = self.arch.addr_width() + 2);
         let mut acc = String::new();
         for ins in self.insns.iter() {
             let insstr = format!("{}", ins);             
@@ -254,7 +962,64 @@ impl<'a> Gadget<'a> {
         }
         let insstr = format!("{}", self.root.root);             
         acc.push_str(&format!("{}", insstr.red()));
-        println!("{}   {}", addr.yellow(), acc);
+        if let Some(notes) = self.notes() {
+            acc.push_str(&format!("   {}", notes.dimmed()));
+        }
+        match self.symbol() {
+            Some(symbol) => println!("{} {}   {}", addr.yellow(), format!("<{}>", symbol).green(), acc),
+            None => println!("{}   {}", addr.yellow(), acc),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use capstone::arch::riscv::RiscVReg;
+
+    use super::*;
+    use crate::cache::InsnCache;
+    use crate::core;
+
+    fn reg(reg: RiscVReg::Type) -> RegId {
+        return RegId(reg as u16);
+    }
+
+    /// Gadget made of the whole of `code`
+    fn gadget(code: &[u8]) -> Gadget {
+        let cache = InsnCache::build(Arch::RV64, code, 0x1000);
+        let gadget = core::gadget_at(&cache, 0, 16).unwrap();
+        let size: usize = gadget.insns().iter().chain([gadget.root()]).map(|ins| ins.bytes().len()).sum();
+        assert_eq!(size, code.len(), "code is not a single gadget");
+        return gadget;
+    }
+
+    #[test]
+    fn table_dispatcher() {
+        // ld a5, 0(s0); addi s0, s0, 8; jr a5
+        let g = gadget(&[0x1c, 0x60, 0x21, 0x04, 0x82, 0x87]);
+        assert_eq!(g.dispatch(), Some(Dispatch {reg: reg(RISCV_REG_S0), stride: Stride::Imm(8), table: None}));
+        assert!(g.classes().contains(&GadgetClass::Dispatcher));
+    }
+
+    #[test]
+    fn indexed_dispatcher() {
+        // slli a4, a2, 3; add a4, a4, s1; ld a5, 0(a4); addi a2, a2, 1; jr a5
+        let g = gadget(&[0x13, 0x17, 0x36, 0x00, 0x26, 0x97, 0x1c, 0x63, 0x05, 0x06, 0x82, 0x87]);
+        assert_eq!(g.dispatch(), Some(Dispatch {reg: reg(RISCV_REG_A2), stride: Stride::Imm(8), table: Some(reg(RISCV_REG_S1))}));
+    }
+
+    #[test]
+    fn epilogues_are_not_dispatchers() {
+        // ldsp ra, 0x18(sp); addi16sp sp, 0x20; jr ra
+        let g = gadget(&[0xe2, 0x60, 0x05, 0x61, 0x82, 0x80]);
+        assert_eq!(g.kind(), RootKind::Return);
+        assert_eq!(g.dispatch(), None);
+        assert!(!g.classes().contains(&GadgetClass::Dispatcher));
+        // ldsp a5, 8(sp); addi sp, sp, 16; jr a5
+        let g = gadget(&[0xa2, 0x67, 0x41, 0x01, 0x82, 0x87]);
+        assert_eq!(g.kind(), RootKind::Jump);
+        assert_eq!(g.dispatch(), None);
     }
 
 }
@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+use std::fs;
+
+use serde_json::{json, Value};
+
+use crate::core::{self, Arch};
+use crate::err::RVError;
+use crate::gadget::{Gadget, RootKind};
+use crate::scanner::Selection;
+use crate::symbols::Symbols;
+
+/// Layout version of the index files
+const VERSION: u64 = 1;
+
+/// FNV-1a hash of the indexed file, to notice when it changes
+pub fn file_hash(data: &[u8]) -> u64 {
+    return data.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3));
+}
+
+/// Gadgets of a binary saved on disk, to be filtered again without decoding it
+pub struct Index {
+    /// Canonical path of the indexed file
+    pub path: String,
+    pub hash: u64,
+    pub arch: Arch,
+    /// Address at which the image is loaded
+    pub base: u64,
+    /// Maximum number of instructions before the root of the indexed gadgets
+    pub max: usize,
+    pub gadgets: Vec<Gadget>,
+    /// Addresses of the `ecall` instructions, with their constant syscall number
+    /// under `max`, see [`Index::sites`]
+    pub sites: Vec<(u64, Option<u64>)>,
+    pub symbols: Symbols,
+}
+
+impl Index {
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "version": VERSION,
+            "path": self.path,
+            "hash": format!("{:016x}", self.hash),
+            "arch": match self.arch {
+                Arch::RV32 => "rv32",
+                Arch::RV64 => "rv64",
+            },
+            "base": self.base,
+            "max": self.max,
+            "symbols": self.symbols.to_json(),
+            "sites": self.sites,
+            "gadgets": self.gadgets.iter().map(|g| g.to_record()).collect::<Vec<Value>>(),
+        })
+    }
+
+    pub fn from_json(index: &Value) -> Result<Self, RVError> {
+        let bad = || RVError {msg: String::from("corrupted index")};
+        if index["version"].as_u64() != Some(VERSION) {
+            return Err(RVError {msg: String::from("the index was built by another version of rv, index the file again")});
+        }
+        let arch = core::arch_from_str(index["arch"].as_str().ok_or_else(bad)?)?;
+        let hash = u64::from_str_radix(index["hash"].as_str().ok_or_else(bad)?, 16).map_err(|_| bad())?;
+        let sites = index["sites"].as_array().ok_or_else(bad)?.iter()
+            .map(|site| Ok((site.get(0).and_then(Value::as_u64).ok_or_else(bad)?, site.get(1).and_then(Value::as_u64))))
+            .collect::<Result<Vec<_>, RVError>>()?;
+        let gadgets = index["gadgets"].as_array().ok_or_else(bad)?.iter()
+            .map(|g| Gadget::from_record(g, arch))
+            .collect::<Result<Vec<_>, RVError>>()?;
+        return Ok(Index {
+            path: String::from(index["path"].as_str().ok_or_else(bad)?),
+            hash,
+            arch,
+            base: index["base"].as_u64().ok_or_else(bad)?,
+            max: index["max"].as_u64().ok_or_else(bad)? as usize,
+            gadgets,
+            sites,
+            symbols: Symbols::from_json(&index["symbols"])?,
+        });
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), RVError> {
+        return fs::write(path, self.to_json().to_string())
+            .map_err(|e| RVError {msg: format!("Failed to write '{}'. {}", path, e)});
+    }
+
+    pub fn load(path: &str) -> Result<Self, RVError> {
+        let data = fs::read(path).map_err(|e| RVError {msg: format!("Failed to read '{}'. {}", path, e)})?;
+        let index = serde_json::from_slice(&data).map_err(|_| RVError {msg: format!("'{}' is not a gadget index", path)})?;
+        return Index::from_json(&index);
+    }
+
+    /// Gadgets a search with at most `max` instructions before the root finds,
+    /// in the same order, rebuilt from the indexed ones
+    pub fn gadgets(&self, max: usize) -> Vec<Gadget> {
+        let mut gadgets = Vec::new();
+        let mut rest = &self.gadgets[..];
+        while let Some(first) = rest.first() {
+            let len = rest.iter().take_while(|g| g.kind() == first.kind() && g.root().address() == first.root().address()).count();
+            let group: Vec<&Gadget> = rest[..len].iter().collect();
+            walk(&group, 0, max, &mut gadgets);
+            if first.kind() == RootKind::Syscall {
+                gadgets.push(first.truncated(0));
+            }
+            rest = &rest[len..];
+        }
+        return gadgets;
+    }
+
+    /// Unique gadgets a [`Scanner`](crate::Scanner) searching with at most `max`
+    /// instructions before the root and the same `selection` finds, in the same order
+    pub fn search(&self, max: usize, selection: &Selection) -> Vec<Gadget> {
+        let mut seen = HashSet::new();
+        return selection.select(self.gadgets(max), self.arch).into_iter()
+            .filter(|g| seen.insert(g.clone()))
+            .collect();
+    }
+
+    /// Addresses of the `ecall` instructions, with the syscall number a search
+    /// with at most `max` instructions before them finds
+    pub fn sites(&self, max: usize) -> Vec<(u64, Option<u64>)> {
+        let gadgets: Vec<Gadget> = self.gadgets(max).into_iter().filter(|g| g.kind() == RootKind::Syscall).collect();
+        return self.sites.iter()
+            .map(|(addr, _)| (*addr, gadgets.iter().filter(|g| g.root().address() == *addr).find_map(|g| g.syscall())))
+            .collect();
+    }
+
+    /// Checks that the indexed file did not change since it was indexed
+    pub fn check(&self) -> Result<(), RVError> {
+        let data = fs::read(&self.path).map_err(|e| RVError {msg: format!("Failed to read the indexed file '{}'. {}", self.path, e)})?;
+        if file_hash(&data) != self.hash {
+            return Err(RVError {msg: format!("'{}' changed since it was indexed, index it again", self.path)});
+        }
+        return Ok(());
+    }
+
+}
+
+/// Walks the indexed gadgets of a root sharing their last `depth` instructions
+/// like `core::find_gadgets_at_root` walks the code, the indexed gadgets holding
+/// every path of the search tree
+fn walk(group: &[&Gadget], depth: usize, max: usize, gadgets: &mut Vec<Gadget>) -> bool {
+    let mut found = false;
+
+    if max == 0 {
+        return false;
+    }
+    // Previous instructions, the shortest first
+    let at = |g: &Gadget| g.insns().len().checked_sub(depth + 1).map(|i| g.insns()[i].address());
+    let mut prev: Vec<u64> = group.iter().filter_map(|g| at(g)).collect();
+    prev.sort_by(|a, b| b.cmp(a));
+    prev.dedup();
+    for addr in prev {
+        let next: Vec<&Gadget> = group.iter().copied().filter(|g| at(g) == Some(addr)).collect();
+        if !walk(&next, depth + 1, max - 1, gadgets) {
+            gadgets.push(next[0].truncated(depth + 1));
+            found = true;
+        }
+    }
+    return found;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Scanner;
+    use crate::gadget::KindFilter;
+
+    /// `ret` epilogues, `ecall; ret`, a dispatcher and gadgets jumping through `s3`
+    const CODE: [u8; 50] = [
+        0x02, 0x65, 0xa2, 0x65, 0xe2, 0x60, 0x05, 0x61, 0x82, 0x80, 0xa2, 0x68,
+        0xc2, 0x60, 0x05, 0x61, 0x82, 0x80, 0x73, 0x00, 0x00, 0x00, 0x82, 0x80,
+        0x1c, 0x60, 0x21, 0x04, 0x82, 0x87, 0x52, 0x85, 0x82, 0x89, 0xc2, 0x65,
+        0x82, 0x89, 0x93, 0x08, 0xd0, 0x0d, 0x82, 0x89, 0x73, 0x00, 0x00, 0x00,
+        0x82, 0x89,
+    ];
+
+    fn scanner() -> Scanner<'static> {
+        return Scanner::new(&CODE).base(0x8000).arch(Arch::RV64).max_depth(4);
+    }
+
+    fn addresses(gadgets: &[Gadget]) -> Vec<(u64, String)> {
+        return gadgets.iter().map(|g| (g.address(), g.text())).collect();
+    }
+
+    #[test]
+    fn save_and_load() {
+        let (mut gadgets, syscalls, sites) = scanner().index_gadgets();
+        assert_eq!(addresses(&gadgets), addresses(&scanner().all_gadgets()));
+        assert_eq!(addresses(&syscalls), addresses(&scanner().kinds(KindFilter::Syscall).all_gadgets()));
+        assert_eq!(sites, scanner().syscall_sites());
+        gadgets.extend(syscalls);
+        let index = Index {
+            path: String::from("code.bin"),
+            hash: file_hash(&CODE),
+            arch: Arch::RV64,
+            base: 0x8000,
+            max: 4,
+            gadgets,
+            sites,
+            symbols: Symbols::default(),
+        };
+        let path = std::env::temp_dir().join(format!("rv-index-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        index.save(path).unwrap();
+        let loaded = Index::load(path);
+        fs::remove_file(path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!((loaded.path.as_str(), loaded.hash, loaded.arch, loaded.base, loaded.max), ("code.bin", index.hash, Arch::RV64, 0x8000, 4));
+        assert_eq!(addresses(&loaded.gadgets), addresses(&index.gadgets));
+        assert_eq!(loaded.sites, index.sites);
+        for max in 1..=4 {
+            let selection = Selection::default();
+            assert_eq!(addresses(&loaded.search(max, &selection)), addresses(&scanner().max_depth(max).scan().collect::<Vec<_>>()));
+            let selection = Selection {kinds: KindFilter::Syscall, ..Selection::default()};
+            assert_eq!(addresses(&loaded.search(max, &selection)), addresses(&scanner().max_depth(max).kinds(KindFilter::Syscall).scan().collect::<Vec<_>>()));
+            assert_eq!(loaded.sites(max), scanner().max_depth(max).syscall_sites());
+        }
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let index = json!({"version": VERSION + 1});
+        let Err(e) = Index::from_json(&index) else {
+            panic!("an index of another version was loaded");
+        };
+        assert!(e.msg.contains("another version"));
+    }
+
+}
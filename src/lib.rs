@@ -0,0 +1,23 @@
+#![allow(clippy::needless_return, clippy::too_many_arguments)]
+
+//! Risc-V JOP gadget finder
+//!
+//! The [`Scanner`] builder is the entry point of the library, the `rv` binary
+//! being a command line front-end over it.
+
+pub mod cache;
+pub mod chain;
+pub mod core;
+pub mod diff;
+pub mod emu;
+pub mod err;
+pub mod expr;
+pub mod gadget;
+pub mod index;
+pub mod loader;
+pub mod query;
+pub mod scanner;
+pub mod symbols;
+pub mod syscalls;
+
+pub use scanner::Scanner;
@@ -0,0 +1,235 @@
+use crate::core::CodeRegion;
+use crate::err::RVError;
+
+/// Container of the analyzed code
+#[derive (Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    Elf,
+    Raw,
+    IHex,
+    SRec,
+}
+
+pub fn input_format_from_str(format: &str) -> Result<InputFormat, RVError> {
+    match format {
+        "elf" => Ok(InputFormat::Elf),
+        "raw" => Ok(InputFormat::Raw),
+        "ihex" => Ok(InputFormat::IHex),
+        "srec" => Ok(InputFormat::SRec),
+        _ => Err(RVError {msg: String::from("not a supported input format (elf, raw, ihex, srec)")}),
+    }
+}
+
+/// Guesses the format from the file content. Anything that is neither an
+/// Intel HEX nor an S-record file is handled as ELF.
+pub fn detect(data: &[u8]) -> InputFormat {
+    if data.starts_with(b"\x7fELF") {
+        return InputFormat::Elf;
+    }
+    let mut lines = data.split(|&b| b == b'\n')
+        .map(|line| line.trim_ascii())
+        .filter(|line| !line.is_empty());
+    let Some(first) = lines.next() else {
+        return InputFormat::Elf;
+    };
+    let is_hex = |line: &[u8]| line.iter().all(|b| b.is_ascii_hexdigit());
+    match first {
+        [b':', rest @ ..] if is_hex(rest) => InputFormat::IHex,
+        [b'S', b'0'..=b'9', rest @ ..] if is_hex(rest) => InputFormat::SRec,
+        _ => InputFormat::Elf,
+    }
+}
+
+/// Decodes the hex digits of a record and checks that its bytes sum to `sum`
+fn record_bytes(line: &str, lineno: usize, sum: u8) -> Result<Vec<u8>, RVError> {
+    let bytes = crate::core::hex_decode(line)
+        .map_err(|_| RVError {msg: format!("line {}: invalid hex digits", lineno)})?;
+    if bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != sum {
+        return Err(RVError {msg: format!("line {}: bad checksum", lineno)});
+    }
+    return Ok(bytes);
+}
+
+/// Parses an Intel HEX file into `(address, bytes)` records
+fn parse_ihex(text: &str) -> Result<Vec<(u64, Vec<u8>)>, RVError> {
+    let mut chunks = Vec::new();
+    let mut base: u64 = 0;
+
+    for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+        let Some(record) = line.strip_prefix(':') else {
+            return Err(RVError {msg: format!("line {}: missing ':' record mark", i)});
+        };
+        let bytes = record_bytes(record, i, 0)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(RVError {msg: format!("line {}: bad record length", i)});
+        }
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => chunks.push((base + offset, data.to_vec())),
+            0x01 => break,
+            0x02 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u64) << 4,
+            0x04 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u64) << 16,
+            // Start addresses do not describe memory content
+            0x03 | 0x05 => {}
+            t => return Err(RVError {msg: format!("line {}: unsupported record type {:02x}", i, t)}),
+        }
+    }
+    return Ok(chunks);
+}
+
+/// Parses a Motorola S-record file into `(address, bytes)` records
+fn parse_srec(text: &str) -> Result<Vec<(u64, Vec<u8>)>, RVError> {
+    let mut chunks = Vec::new();
+
+    for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, record) = match line.strip_prefix('S').map(|r| r.split_at_checked(1)) {
+            Some(Some((kind, record))) => (kind, record),
+            _ => return Err(RVError {msg: format!("line {}: missing 'S' record mark", i)}),
+        };
+        let bytes = record_bytes(record, i, 0xff)?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(RVError {msg: format!("line {}: bad record length", i)});
+        }
+        let addr_len = match kind {
+            "1" => 2,
+            "2" => 3,
+            "3" => 4,
+            // Header, record counts and start addresses
+            "0" | "5" | "6" | "7" | "8" | "9" => continue,
+            _ => return Err(RVError {msg: format!("line {}: unsupported record type S{}", i, kind)}),
+        };
+        if bytes.len() < addr_len + 2 {
+            return Err(RVError {msg: format!("line {}: bad record length", i)});
+        }
+        let addr = bytes[1..=addr_len].iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+        chunks.push((addr, bytes[addr_len + 1..bytes.len() - 1].to_vec()));
+    }
+    return Ok(chunks);
+}
+
+/// Lays out sparse records as contiguous regions, later records overwriting
+/// earlier ones. Returns the regions content and where each one is mapped.
+fn assemble(chunks: Vec<(u64, Vec<u8>)>) -> Result<(Vec<u8>, Vec<CodeRegion>), RVError> {
+    // Address ranges of the records, merged when they touch or overlap
+    let mut spans: Vec<(u64, u64)> = chunks.iter()
+        .filter(|(_, data)| !data.is_empty())
+        .map(|(addr, data)| (*addr, addr + data.len() as u64))
+        .collect();
+    spans.sort_unstable();
+    let mut regions: Vec<CodeRegion> = Vec::new();
+    let mut size = 0;
+    for (start, end) in spans {
+        match regions.last_mut() {
+            Some(region) if start <= region.addr + region.size as u64 => {
+                let grown = (end.max(region.addr + region.size as u64) - region.addr) as usize;
+                size += grown - region.size;
+                region.size = grown;
+            }
+            _ => {
+                regions.push(CodeRegion {off: size, size: (end - start) as usize, addr: start});
+                size += (end - start) as usize;
+            }
+        }
+    }
+    if regions.is_empty() {
+        return Err(RVError {msg: String::from("The file contains no data")});
+    }
+
+    // Copied in file order, so that a record overwrites the span it shares with earlier ones
+    let mut image = vec![0u8; size];
+    for (addr, data) in chunks.iter().filter(|(_, data)| !data.is_empty()) {
+        let region = &regions[regions.partition_point(|r| r.addr <= *addr) - 1];
+        let off = region.off + (addr - region.addr) as usize;
+        image[off..off + data.len()].copy_from_slice(data);
+    }
+    return Ok((image, regions));
+}
+
+/// Rebuilds the memory content described by an Intel HEX or S-record file
+pub fn load_firmware(data: &[u8], format: InputFormat) -> Result<(Vec<u8>, Vec<CodeRegion>), RVError> {
+    let text = std::str::from_utf8(data).map_err(|_| RVError {msg: String::from("The file is not a text file")})?;
+    let chunks = match format {
+        InputFormat::IHex => parse_ihex(text)?,
+        InputFormat::SRec => parse_srec(text)?,
+        _ => return Err(RVError {msg: String::from("not a firmware format")}),
+    };
+    return assemble(chunks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(data: &str, format: InputFormat) -> (Vec<u8>, Vec<(u64, usize, usize)>) {
+        let (image, regions) = load_firmware(data.as_bytes(), format).unwrap();
+        return (image, regions.iter().map(|r| (r.addr, r.off, r.size)).collect());
+    }
+
+    fn failure(data: &str, format: InputFormat) -> String {
+        return load_firmware(data.as_bytes(), format).unwrap_err().msg;
+    }
+
+    #[test]
+    fn ihex_extended_addresses() {
+        let data = ":0200000480007A\n:080010001300000067800000EE\n:020000021000EC\n:020004008280F8\n:00000001FF\n";
+        assert_eq!(detect(data.as_bytes()), InputFormat::IHex);
+        let (image, regions) = layout(data, InputFormat::IHex);
+        assert_eq!(image, [0x82, 0x80, 0x13, 0, 0, 0, 0x67, 0x80, 0, 0]);
+        assert_eq!(regions, [(0x10004, 0, 2), (0x80000010, 2, 8)]);
+    }
+
+    #[test]
+    fn ihex_gaps_split_regions() {
+        let (image, regions) = layout(":020000000102FB\n:020100000304F6\n:020002000506F1\n", InputFormat::IHex);
+        assert_eq!(image, [1, 2, 5, 6, 3, 4]);
+        assert_eq!(regions, [(0, 0, 4), (0x100, 4, 2)]);
+    }
+
+    #[test]
+    fn later_records_overwrite_earlier_ones() {
+        let (image, regions) = layout(":0400020001020304F0\n:0400000009090909D8\n", InputFormat::IHex);
+        assert_eq!(image, [9, 9, 9, 9, 3, 4]);
+        assert_eq!(regions, [(0, 0, 6)]);
+    }
+
+    #[test]
+    fn nested_records_overwrite_in_file_order() {
+        let (image, regions) = layout(":080000000101010101010101F0\n:020002000202F8\n:03000000030303F4\n", InputFormat::IHex);
+        assert_eq!(image, [3, 3, 3, 2, 1, 1, 1, 1]);
+        assert_eq!(regions, [(0, 0, 8)]);
+    }
+
+    #[test]
+    fn ihex_errors() {
+        assert_eq!(failure(":020000000102FB\n:020100000304F7\n", InputFormat::IHex), "line 2: bad checksum");
+        assert_eq!(failure("020000000102FB\n", InputFormat::IHex), "line 1: missing ':' record mark");
+        assert_eq!(failure(":030000000102FA\n", InputFormat::IHex), "line 1: bad record length");
+        assert_eq!(failure(":0000000AF6\n", InputFormat::IHex), "line 1: unsupported record type 0a");
+        assert_eq!(failure(":00000001FF\n", InputFormat::IHex), "The file contains no data");
+    }
+
+    #[test]
+    fn srec_address_sizes() {
+        let data = "S0060000686472BB\nS107001013000000D5\nS30780000000828076\nS2060200000102F4\nS9030000FC\n";
+        assert_eq!(detect(data.as_bytes()), InputFormat::SRec);
+        let (image, regions) = layout(data, InputFormat::SRec);
+        assert_eq!(image, [0x13, 0, 0, 0, 1, 2, 0x82, 0x80]);
+        assert_eq!(regions, [(0x10, 0, 4), (0x20000, 4, 2), (0x80000000, 6, 2)]);
+    }
+
+    #[test]
+    fn srec_errors() {
+        assert_eq!(failure("S0060000686472BB\nS107001013000000D6\n", InputFormat::SRec), "line 2: bad checksum");
+        assert_eq!(failure("X107001013000000D5\n", InputFormat::SRec), "line 1: missing 'S' record mark");
+        assert_eq!(failure("S407001013000000D5\n", InputFormat::SRec), "line 1: unsupported record type S4");
+        assert_eq!(failure("S107001013000000ZZ\n", InputFormat::SRec), "line 1: invalid hex digits");
+    }
+
+}
@@ -1,27 +1,79 @@
-mod gadget;
-mod err;
-mod query;
-mod core;
+#![allow(clippy::needless_return)]
 
-use std::iter;
 use std::collections::HashSet;
+use std::io::Write;
+use std::thread;
 
 use capstone::prelude::*;
-use elf::{ElfBytes ,endian};
+use capstone::arch::riscv::RiscVReg::RISCV_REG_A7;
 use colored::Colorize;
-use clap::Parser;
+use regex::Regex;
+use clap::{Parser, Subcommand};
+use serde_json::json;
 
-use gadget::OutputMode;
-use query::Query;
+use raccoonv::Scanner;
+use raccoonv::cache::InsnCache;
+use raccoonv::chain::{self, Goal, PayloadFormat};
+use raccoonv::core::{self, Arch, CodeRegion};
+use raccoonv::diff::{self as gdiff, DiffFormat};
+use raccoonv::emu::Emulator;
+use raccoonv::err::RVError;
+use raccoonv::expr::{self, QueryExpr};
+use raccoonv::index::{self, Index};
+use raccoonv::gadget::{self, Gadget, GadgetClass, KindFilter, OutputMode, RootKind};
+use raccoonv::loader::{self, InputFormat};
+use raccoonv::query::{Filter, Query};
+use raccoonv::scanner::Selection;
+use raccoonv::symbols::Symbols;
+use raccoonv::syscalls;
 
 /// Command line tool to find JOP gadgets in a Risc-V application
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Emulate the gadget starting at <addr> and report its effects
+    Verify(VerifyArgs),
+    /// Build a JOP chain reaching <goal> from the gadgets of the binary
+    Chain(ChainArgs),
+    /// Build a ROP stack payload reaching <goal> from the ret gadgets of the binary
+    Rop(RopArgs),
+    /// Save the gadgets of the binary in an index file for `rv query`
+    Index(IndexArgs),
+    /// Filter the gadgets of an index file, with the options of the search
+    Query(Box<QueryArgs>),
+    /// Compare the gadgets of two versions of a binary
+    Diff(DiffArgs),
+}
+
+// Plain `Vec` fields would make clap expect one element per value
+type RegList = Vec<RegId>;
+type Bytes = Vec<u8>;
+
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Path of the target binary
-    #[arg()]
-    path: String,
+    #[arg(required = true)]
+    path: Option<String>,
+
+    #[command(flatten)]
+    filters: Filters,
+
+    #[command(flatten)]
+    load: LoadArgs,
+}
 
+/// Options selecting and displaying gadgets, shared by the search and `rv query`
+#[derive(clap::Args, Debug)]
+struct Filters {
     /// Find dispatcher gadgets
     #[arg(short, long)]
     dispatcher: bool,
@@ -30,110 +82,738 @@ struct Args {
     #[arg(long)]
     inline: bool,
 
+    /// Output format (block, inline, json, jsonl, python)
+    #[arg(long, value_name="fmt", value_parser=gadget::outmode_from_str)]
+    format: Option<OutputMode>,
+
     /// Only search gadgets with at maximum <max> instructions
     #[arg(short, long, default_value="5")]
     max: usize,
 
-    /// Only find gadgets ending with a jump to <reg> register
-    #[arg(short, long, value_name="reg", value_parser=core::reg_from_str)]
-    jr: Option<RegId>,
+    /// Only find gadgets ending with a jump to one of the <reg> registers
+    #[arg(short, long, value_name="reg", value_delimiter=',', value_parser=core::reg_from_str)]
+    jr: Vec<RegId>,
+
+    /// Only find gadgets ending with a return (rop), an indirect jump (jop), an indirect call (call), any of them (all) or an ecall (syscall)
+    #[arg(short, long, value_name="kind", default_value="all", value_parser=gadget::kind_from_str)]
+    kind: KindFilter,
+
+    /// List every ecall site, with the gadgets setting up the syscall number in a7 before one
+    #[arg(long)]
+    syscall: bool,
+
+    /// Only find gadgets where one of the <reg> registers is written to
+    #[arg(short, long, visible_alias="wr-any", value_name="reg", value_delimiter=',', value_parser=core::reg_from_str)]
+    wr: Vec<RegId>,
+
+    /// Only find gadgets where each of the <reg> registers is written to
+    #[arg(long, value_name="reg", value_delimiter=',', value_parser=core::reg_from_str)]
+    wr_all: Vec<RegId>,
+
+    /// Only find gadgets where one of the <imm> immediates is used
+    #[arg(short, long, visible_alias="imm-any", value_name="imm", value_delimiter=',', allow_hyphen_values=true)]
+    imm: Vec<i64>,
+
+    /// Only find gadgets where each of the <imm> immediates is used
+    #[arg(long, value_name="imm", value_delimiter=',', allow_hyphen_values=true)]
+    imm_all: Vec<i64>,
+
+    /// Only find gadgets where one of the <reg> registers is read from
+    #[arg(short, long, visible_alias="rr-any", value_name="reg", value_delimiter=',', value_parser=core::reg_from_str)]
+    rr: Vec<RegId>,
+
+    /// Only find gadgets where each of the <reg> registers is read from
+    #[arg(long, value_name="reg", value_delimiter=',', value_parser=core::reg_from_str)]
+    rr_all: Vec<RegId>,
+
+    /// Only find gadgets containing one of the <ins> instructions
+    #[arg(short, long, visible_alias="op-any", value_name="ins", value_delimiter=',', value_parser=core::ins_from_str)]
+    op: Vec<InsnId>,
+
+    /// Only find gadgets containing each of the <ins> instructions
+    #[arg(long, value_name="ins", value_delimiter=',', value_parser=core::ins_from_str)]
+    op_all: Vec<InsnId>,
+
+    /// Only find gadgets moving the stack pointer by <delta> bytes
+    #[arg(long, value_name="delta", allow_hyphen_values=true)]
+    sp_delta: Option<i64>,
+
+    /// Reject gadgets writing to one of the <regs> registers, as a list or ranges (s0-s11)
+    #[arg(long, value_name="regs", value_parser=core::regs_from_str)]
+    no_write: Vec<RegList>,
+
+    /// Reject gadgets containing one of the <ins> instructions
+    #[arg(long, value_name="ins", value_delimiter=',', value_parser=core::ins_from_str)]
+    no_op: Vec<InsnId>,
+
+    /// Reject gadgets writing to memory
+    #[arg(long)]
+    no_mem_write: bool,
+
+    /// Reject gadgets containing a conditional branch
+    #[arg(long)]
+    no_branch_inside: bool,
+
+    /// Reject gadgets whose address, as laid out in memory, contains one of the <hex> bytes (e.g. 000a0d)
+    #[arg(long, value_name="hex", value_parser=core::hex_decode)]
+    bad_bytes: Option<Bytes>,
+
+    /// With --bad-bytes, use a later entry point of the gadget when its address contains bad bytes
+    #[arg(long, requires="bad_bytes")]
+    later_entries: bool,
+
+    /// Only find gadgets located in a function whose name matches <regex>
+    #[arg(long, value_name="regex", value_parser=Regex::new)]
+    in_func: Option<Regex>,
+
+    /// Reject gadgets located in a function whose name matches <regex>
+    #[arg(long, value_name="regex", value_parser=Regex::new)]
+    exclude_func: Option<Regex>,
+
+    /// Only find gadgets of one of the <class> categories (dispatcher, init, pivot, const, mov, arith, load, store, syscall)
+    #[arg(short, long, value_name="class", value_delimiter=',', value_parser=gadget::class_from_str)]
+    class: Vec<GadgetClass>,
+
+    /// Only find gadgets satisfying the <expr> boolean expression, e.g. 'writes(a0) and not reads(sp)'
+    #[arg(short, long, id="expr", value_name="expr", value_parser=expr::parse)]
+    query: Option<QueryExpr>,
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// Path of the target binary
+    #[arg()]
+    path: String,
+
+    /// Address of the first instruction of the gadget
+    #[arg(value_parser=core::int_from_str)]
+    addr: u64,
+
+    /// Maximum number of instructions before the gadget root
+    #[arg(short, long, default_value="16")]
+    max: usize,
+
+    /// Give a concrete initial value to a register, registers are symbolic otherwise
+    #[arg(short, long, value_name="reg=val", value_parser=reg_val_from_str)]
+    set: Vec<(RegId, u64)>,
+
+    /// Give a concrete initial content to memory, as hex bytes in memory order
+    #[arg(long, value_name="addr=bytes", value_parser=mem_from_str)]
+    mem: Vec<(u64, Vec<u8>)>,
+
+    #[command(flatten)]
+    load: LoadArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ChainArgs {
+    /// Path of the target binary
+    #[arg()]
+    path: String,
+
+    /// Registers to set then optionally a system call, e.g. a0=0x1000,a7=221,ecall
+    #[arg(value_parser=chain::goal_from_str)]
+    goal: Goal,
+
+    /// Only use gadgets with at maximum <max> instructions
+    #[arg(short, long, default_value="5")]
+    max: usize,
+
+    /// Address of the first dispatch table entry, left symbolic otherwise
+    #[arg(long, value_name="addr", value_parser=core::int_from_str)]
+    table: Option<u64>,
+
+    #[command(flatten)]
+    load: LoadArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct RopArgs {
+    /// Path of the target binary
+    #[arg()]
+    path: String,
+
+    /// Registers to set then optionally a system call, e.g. a0=0x1000,a7=221,ecall
+    #[arg(value_parser=chain::goal_from_str)]
+    goal: Goal,
+
+    /// Only use gadgets with at maximum <max> instructions
+    #[arg(short, long, default_value="5")]
+    max: usize,
+
+    /// Output format of the payload (text, hex, bytes, python)
+    #[arg(long, value_name="fmt", default_value="text", value_parser=chain::payload_format_from_str)]
+    emit: PayloadFormat,
+
+    #[command(flatten)]
+    load: LoadArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct IndexArgs {
+    /// Path of the target binary
+    #[arg()]
+    path: String,
+
+    /// Path of the index file to write
+    #[arg(short, long, value_name="db")]
+    output: String,
+
+    /// Index gadgets with at maximum <max> instructions, the limit of later queries
+    #[arg(short, long, default_value="5")]
+    max: usize,
 
-    /// Only find gadgets where the <reg> register is written to
-    #[arg(short, long, value_name="reg", value_parser=core::reg_from_str)]
-    wr: Option<RegId>,
+    #[command(flatten)]
+    load: LoadArgs,
+}
 
-    /// Only find gadgets where the <imm> immediate is used
-    #[arg(short, long, value_name="imm")]
-    imm: Option<i64>,
+#[derive(clap::Args, Debug)]
+struct QueryArgs {
+    /// Path of the index file written by `rv index`
+    #[arg()]
+    db: String,
 
-    /// Only find gadgets where the <reg> register is read from
-    #[arg(short, long, value_name="reg", value_parser=core::reg_from_str)]
-    rr: Option<RegId>,
+    #[command(flatten)]
+    filters: Filters,
+}
 
-    /// Only find gadgets containing the <ins> instruction
-    #[arg(short, long, value_name="ins", value_parser=core::ins_from_str)]
-    op: Option<InsnId>,
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// Path of the old version of the binary
+    #[arg()]
+    old: String,
 
-    /// Process raw code instead of elf file
+    /// Path of the new version of the binary
+    #[arg()]
+    new: String,
+
+    /// Only compare gadgets with at maximum <max> instructions
+    #[arg(short, long, default_value="5")]
+    max: usize,
+
+    /// Only compare gadgets ending with a jump to one of the <reg> registers
+    #[arg(short, long, value_name="reg", value_delimiter=',', value_parser=core::reg_from_str)]
+    jr: Vec<RegId>,
+
+    /// Only compare gadgets ending with a return (rop), an indirect jump (jop), an indirect call (call), any of them (all) or an ecall (syscall)
+    #[arg(short, long, value_name="kind", default_value="all", value_parser=gadget::kind_from_str)]
+    kind: KindFilter,
+
+    /// Also list the gadgets found at the same address in both versions
     #[arg(long)]
+    stable: bool,
+
+    /// Output format (text, json)
+    #[arg(long, value_name="fmt", default_value="text", value_parser=gdiff::diff_format_from_str)]
+    format: DiffFormat,
+
+    #[command(flatten)]
+    load: LoadArgs,
+}
+
+fn reg_val_from_str(arg: &str) -> Result<(RegId, u64), RVError> {
+    let (reg, val) = arg.split_once('=').ok_or(RVError {msg: String::from("expected <reg>=<val>")})?;
+    return Ok((core::reg_from_str(reg)?, core::int_from_str(val)?));
+}
+
+fn mem_from_str(arg: &str) -> Result<(u64, Vec<u8>), RVError> {
+    let (addr, hex) = arg.split_once('=').ok_or(RVError {msg: String::from("expected <addr>=<bytes>")})?;
+    let bytes = core::hex_decode(hex)?;
+    return Ok((core::int_from_str(addr)?, bytes));
+}
+
+/// Options locating the code of the input files, shared by every command
+#[derive(clap::Args, Debug)]
+struct LoadArgs {
+    /// Process raw code instead of elf file, same as --input-format raw
+    #[arg(long, conflicts_with="input_format")]
     raw: bool,
+
+    /// Format of the input file (elf, raw, ihex, srec). Detected from its content by default
+    #[arg(long, value_name="fmt", value_parser=loader::input_format_from_str)]
+    input_format: Option<InputFormat>,
+
+    /// Address at which raw code is loaded [default: 0]
+    #[arg(long, value_name="addr", value_parser=core::int_from_str)]
+    load_addr: Option<u64>,
+
+    /// Rebase printed addresses so that the image starts at <addr>, PIE binaries being relative to their lowest PT_LOAD
+    #[arg(long, value_name="addr", value_parser=core::int_from_str)]
+    base: Option<u64>,
+
+    /// Number of threads used to search gadgets [default: number of CPUs]
+    #[arg(short, long, value_name="n")]
+    threads: Option<usize>,
+
+    /// Base ISA of the code (rv32, rv64). Detected from the ELF class by default, rv32 for other formats
+    #[arg(long, value_name="arch", value_parser=core::arch_from_str)]
+    arch: Option<Arch>,
+}
+
+impl LoadArgs {
+
+    fn options(&self) -> LoadOptions {
+        return LoadOptions {
+            format: if self.raw { Some(InputFormat::Raw) } else { self.input_format },
+            load_addr: self.load_addr,
+            base: self.base,
+            arch: self.arch,
+        };
+    }
+
+}
+
+/// Where and how the input file is loaded
+struct LoadOptions {
+    format: Option<InputFormat>,
+    load_addr: Option<u64>,
+    base: Option<u64>,
+    arch: Option<Arch>,
+}
+
+/// Loaded input file
+struct Input {
+    data: Vec<u8>,
+    regions: Vec<CodeRegion>,
+    /// Address at which the image is loaded, after rebasing
+    base: u64,
+    arch: Arch,
+    symbols: Symbols,
+    /// Hash of the file content, see `index::file_hash`
+    hash: u64,
+}
+
+/// Reads the input file and locates its code, reporting failures on stderr
+fn load(path: &str, opts: &LoadOptions) -> Option<Input> {
+    let data = match std::fs::read(path) {
+        Ok(raw) => raw,
+        Err(e) => {
+            eprintln!("{} Failed to read '{}'. {}", "ERROR:".red(), path, e);
+            return None;
+        }
+    };
+    let hash = index::file_hash(&data);
+
+    let mut symbols = Symbols::default();
+    let loaded = match opts.format.unwrap_or_else(|| loader::detect(&data)) {
+        InputFormat::Raw => {
+            let addr = opts.load_addr.unwrap_or(0);
+            let region = CodeRegion {off: 0, size: data.len(), addr};
+            Ok((data, Arch::RV32, vec![region], addr))
+        }
+        InputFormat::Elf => core::load_elf(&data).map(|(arch, regions, base)| {
+            symbols = Symbols::from_elf(&data).unwrap_or_default();
+            (data, arch, regions, base)
+        }),
+        format => loader::load_firmware(&data, format).map(|(image, regions)| {
+            let base = regions[0].addr;
+            (image, Arch::RV32, regions, base)
+        }),
+    };
+    let (data, arch, mut regions, image_base) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{} Failed to load '{}'. {}", "ERROR:".red(), path, e.msg);
+            return None;
+        }
+    };
+    if let Some(base) = opts.base {
+        core::rebase(&mut regions, image_base, base);
+        symbols.rebase(image_base, base);
+    }
+    let base = opts.base.unwrap_or(image_base);
+    return Some(Input {data, regions, base, arch: opts.arch.unwrap_or(arch), symbols, hash});
+}
+
+/// Scanners of every code region
+fn scanners(input: &Input, threads: Option<usize>) -> impl Iterator<Item=Scanner<'_>> {
+    let threads = threads.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    return input.regions.iter().map(move |region| {
+        Scanner::new(&input.data[region.off..(region.off + region.size)])
+            .base(region.addr)
+            .arch(input.arch)
+            .threads(threads)
+    });
+}
+
+/// Unique gadgets of every code region, named after their function
+fn find_gadgets(input: &Input, threads: Option<usize>, configure: impl Fn(Scanner) -> Scanner) -> Vec<Gadget> {
+    let mut seen = HashSet::new();
+    let mut gadgets = Vec::new();
+
+    for scanner in scanners(input, threads) {
+        for mut gadget in configure(scanner).scan() {
+            gadget.symbolize(&input.symbols);
+            if seen.insert(gadget.clone()) {
+                gadgets.push(gadget);
+            }
+        }
+    }
+    return gadgets;
 }
 
 fn main() {
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Verify(args)) => verify(args),
+        Some(Command::Chain(args)) => chain(args),
+        Some(Command::Rop(args)) => rop(args),
+        Some(Command::Index(args)) => index(args),
+        Some(Command::Query(args)) => query(*args),
+        Some(Command::Diff(args)) => diff(args),
+        None => search(cli.args),
+    }
+}
 
-    /* Arguments parsing */
+fn verify(args: VerifyArgs) {
+    let opts = args.load.options();
+    let Some(Input {data, regions, arch, symbols, ..}) = load(&args.path, &opts) else {
+        return;
+    };
+    let Some(region) = regions.iter().find(|r| r.addr <= args.addr && args.addr - r.addr < r.size as u64) else {
+        eprintln!("{} {:#x} is not in an executable region of '{}'", "ERROR:".red(), args.addr, &args.path);
+        return;
+    };
 
-    let args = Args::parse();
-    let outmode = match args.inline {
-        true => OutputMode::Inline,
-        false => OutputMode::Block,
+    let code = &data[region.off..(region.off + region.size)];
+    let cache = InsnCache::build(arch, code, region.addr);
+    let mut gadget = match core::gadget_at(&cache, args.addr - region.addr, args.max) {
+        Ok(gadget) => gadget,
+        Err(e) => {
+            eprintln!("{} {}", "ERROR:".red(), e.msg);
+            return;
+        }
     };
-    let query = Query::create_from(args.rr, args.wr, args.imm, args.op, args.dispatcher);
+    gadget.symbolize(&symbols);
 
-    /* ELF parsing */
+    let mut emu = Emulator::new(arch);
+    for (reg, val) in &args.set {
+        if let Err(e) = emu.set_reg(*reg, *val) {
+            eprintln!("{} {}", "ERROR:".red(), e.msg);
+            return;
+        }
+    }
+    for (addr, bytes) in &args.mem {
+        emu.set_mem(*addr, bytes);
+    }
 
-    let data = match std::fs::read(&args.path) {
-        Ok(raw) => raw,
+    gadget.print(&Query::default(), OutputMode::Block);
+    println!();
+    let effects = match emu.run(&gadget) {
+        Ok(effects) => effects,
         Err(e) => {
-            eprintln!("{} Failed to read '{}'. {}", "ERROR:".red(), &args.path, e);
+            eprintln!("{} {}", "ERROR:".red(), e.msg);
             return;
         }
     };
 
-    let (off, size, addr) = if args.raw {
-        (0, data.len(), 0)
-    } else {
-        let elf = match ElfBytes::<endian::AnyEndian>::minimal_parse(&data) {
-            Ok(elf) => elf,
-            Err(_) => {
-                eprintln!("{} Failed to parse '{}'. Make sure to provide a valid ELF file", "ERROR:".red(), &args.path);
-                return;
-            }
-        };
-        if elf.ehdr.e_machine != elf::abi::EM_RISCV || elf.ehdr.class != elf::file::Class::ELF32 {
-            eprintln!("{} racoonv only supports Risc-V binaries (ISA RV32IC)", "ERROR:".red());
+    println!("{}", "Registers:".bold());
+    for (reg, val) in &effects.regs {
+        println!("  {:<4} = {}", core::reg_name(*reg), val);
+    }
+    if !effects.writes.is_empty() {
+        println!("{}", "Memory writes:".bold());
+        for write in &effects.writes {
+            println!("  [{}] <- {} ({} bytes)", write.addr, write.value, write.size);
+        }
+    }
+    match &effects.target {
+        Some(target) => println!("{} {}", "Jumps to:".bold(), target.to_string().red()),
+        None => println!("{} falls through", "Jumps to:".bold()),
+    }
+    for note in &effects.notes {
+        println!("{} {}", "Note:".dimmed(), note);
+    }
+}
+
+fn chain(args: ChainArgs) {
+    let opts = args.load.options();
+    let Some(input) = load(&args.path, &opts) else {
+        return;
+    };
+    let gadgets = find_gadgets(&input, args.load.threads, |scanner| scanner.max_depth(args.max));
+
+    match chain::compile(&gadgets, &args.goal, input.arch) {
+        Ok(mut chain) => {
+            chain.table = args.table;
+            print!("{}", chain);
+        }
+        Err(e) => eprintln!("{} Failed to build a chain. {}", "ERROR:".red(), e.msg),
+    }
+}
+
+fn rop(args: RopArgs) {
+    let opts = args.load.options();
+    let Some(input) = load(&args.path, &opts) else {
+        return;
+    };
+    let gadgets = find_gadgets(&input, args.load.threads, |scanner| scanner.max_depth(args.max));
+
+    let chain = match chain::compile_rop(&gadgets, &args.goal, input.arch) {
+        Ok(chain) => chain,
+        Err(e) => {
+            eprintln!("{} Failed to build a chain. {}", "ERROR:".red(), e.msg);
             return;
         }
-        match core::get_code(&elf) {
-            Ok(text) => text,
-            Err(e) => {
-                eprintln!("{} Failed to find code in '{}'. {}", "ERROR:".red(), &args.path, e);
-                return;
+    };
+    match args.emit {
+        PayloadFormat::Text => print!("{}", chain),
+        PayloadFormat::Hex => println!("{}", chain.hex()),
+        PayloadFormat::Bytes => {
+            if let Err(e) = std::io::stdout().write_all(&chain.payload) {
+                eprintln!("{} Failed to write the payload. {}", "ERROR:".red(), e);
             }
         }
+        PayloadFormat::Python => print!("{}", chain.python()),
+    }
+}
+
+fn diff(args: DiffArgs) {
+    let opts = args.load.options();
+    let (Some(old), Some(new)) = (load(&args.old, &opts), load(&args.new, &opts)) else {
+        return;
+    };
+    if old.arch != new.arch {
+        eprintln!("{} '{}' and '{}' do not share the same base ISA", "ERROR:".red(), &args.old, &args.new);
+        return;
+    }
+
+    // Every address of a gadget is compared, not only the first one
+    let gadgets = |input: &Input| -> Vec<Gadget> {
+        scanners(input, args.load.threads)
+            .flat_map(|scanner| scanner.max_depth(args.max).jump_regs(args.jr.clone()).kinds(args.kind).all_gadgets())
+            .map(|mut gadget| {
+                gadget.symbolize(&input.symbols);
+                gadget
+            })
+            .collect()
+    };
+    let diff = gdiff::diff(&gadgets(&old), &gadgets(&new), new.arch);
+    match (args.format, args.stable) {
+        (DiffFormat::Json, _) => println!("{:#}", diff.to_json()),
+        (DiffFormat::Text, true) => print!("{:#}", diff),
+        (DiffFormat::Text, false) => print!("{}", diff),
+    }
+}
+
+fn search(args: Args) {
+    let path = args.path.unwrap_or_default();
+    let opts = args.load.options();
+    let Some(input) = load(&path, &opts) else {
+        return;
+    };
+    let f = &args.filters;
+    let query = build_query(f);
+
+    let selection = build_selection(f, &query);
+    let gadgets = find_gadgets(&input, args.load.threads, |scanner| scanner.max_depth(f.max).selection(selection.clone()));
+    let sites: Vec<(u64, Option<u64>)> = if f.syscall {
+        scanners(&input, args.load.threads).flat_map(|scanner| scanner.max_depth(f.max).syscall_sites()).collect()
+    } else {
+        Vec::new()
+    };
+    report(f, &query, gadgets, sites, Origin {path: &path, symbols: &input.symbols, arch: input.arch, base: input.base});
+}
+
+fn index(args: IndexArgs) {
+    let opts = args.load.options();
+    let Some(input) = load(&args.path, &opts) else {
+        return;
+    };
+    // Gadgets are kept per root, duplicates included, for queries with a lower maximum
+    let mut gadgets: Vec<Gadget> = Vec::new();
+    let mut sites = Vec::new();
+    for scanner in scanners(&input, args.load.threads) {
+        let (all, syscalls, found) = scanner.max_depth(args.max).index_gadgets();
+        gadgets.extend(all);
+        gadgets.extend(syscalls);
+        sites.extend(found);
+    }
+
+    let path = std::fs::canonicalize(&args.path).map_or(args.path.clone(), |p| p.to_string_lossy().into_owned());
+    let index = Index {
+        path,
+        hash: input.hash,
+        arch: input.arch,
+        base: input.base,
+        max: args.max,
+        gadgets,
+        sites,
+        symbols: input.symbols,
+    };
+    match index.save(&args.output) {
+        Ok(()) => println!("Indexed {} gadgets of '{}' in '{}'.", index.gadgets.iter().collect::<HashSet<_>>().len(), &args.path, &args.output),
+        Err(e) => eprintln!("{} {}", "ERROR:".red(), e.msg),
+    }
+}
+
+fn query(args: QueryArgs) {
+    let index = match Index::load(&args.db).and_then(|index| index.check().map(|_| index)) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("{} {}", "ERROR:".red(), e.msg);
+            return;
+        }
+    };
+    let f = &args.filters;
+    if f.max > index.max {
+        eprintln!("{} The index holds gadgets of at most {} instructions, index the file again with -m {}", "ERROR:".red(), index.max, f.max);
+        return;
+    }
+    let query = build_query(f);
+    let mut gadgets = index.search(f.max, &build_selection(f, &query));
+    for gadget in &mut gadgets {
+        gadget.symbolize(&index.symbols);
+    }
+    let sites = if f.syscall { index.sites(f.max) } else { Vec::new() };
+    report(f, &query, gadgets, sites, Origin {path: &index.path, symbols: &index.symbols, arch: index.arch, base: index.base});
+}
+
+fn build_query(f: &Filters) -> Query {
+    let mut query = Query::create_from(
+        Filter::new(f.rr.clone(), f.rr_all.clone()),
+        Filter::new(f.wr.clone(), f.wr_all.clone()),
+        Filter::new(f.imm.clone(), f.imm_all.clone()),
+        Filter::new(f.op.clone(), f.op_all.clone()),
+        f.dispatcher,
+    );
+    query.sp_delta = f.sp_delta;
+    query.expr = f.query.clone();
+    query.no_write = f.no_write.iter().flatten().copied().collect();
+    query.no_op = f.no_op.clone();
+    query.no_mem_write = f.no_mem_write;
+    query.no_branch_inside = f.no_branch_inside;
+    query.classes = f.class.clone();
+    return query;
+}
+
+/// Search filters shared by the scanner and the index
+fn build_selection(f: &Filters, query: &Query) -> Selection {
+    return Selection {
+        jr: f.jr.clone(),
+        kinds: if f.syscall { KindFilter::Syscall } else { f.kind },
+        query: query.clone(),
+        bad: f.bad_bytes.clone().unwrap_or_default(),
+        later: f.later_entries,
     };
+}
 
-    /* Gadgets finding & displaying */
+/// Binary the reported gadgets come from
+struct Origin<'a> {
+    path: &'a str,
+    symbols: &'a Symbols,
+    arch: Arch,
+    base: u64,
+}
 
-    let cs = Capstone::new()
-        .riscv()
-        .mode(arch::riscv::ArchMode::RiscV32)
-        .extra_mode(iter::once(arch::riscv::ArchExtraMode::RiscVC))
-        .detail(true)
-        .build()
-        .expect("Failed to create Capstone object");
+/// Applies the function filters and prints the gadgets and ecall sites
+fn report(f: &Filters, query: &Query, mut gadgets: Vec<Gadget>, sites: Vec<(u64, Option<u64>)>, origin: Origin) {
+    let Origin {path, symbols, arch, base} = origin;
+    let outmode = match (f.format, f.inline) {
+        (Some(mode), _) => mode,
+        (None, true) => OutputMode::Inline,
+        (None, false) => OutputMode::Block,
+    };
+    if (f.in_func.is_some() || f.exclude_func.is_some()) && symbols.is_empty() {
+        eprintln!("{} '{}' has no function symbols to match --in-func or --exclude-func against", "WARNING:".yellow(), path);
+    }
 
-    let code = &data[off..(off + size)];
-    let gadget_roots = core::find_gadget_roots(&cs, &code, args.jr);
+    let a7 = RegId(RISCV_REG_A7 as u16);
+    gadgets.retain(|gadget| {
+        // Bare ecall sites are listed on their own
+        if f.syscall && !gadget.insns().iter().any(|ins| ins.regs_written().contains(&a7)) {
+            return false;
+        }
+        let func = symbols.lookup(gadget.address()).map(|(name, _)| name);
+        if f.in_func.as_ref().is_some_and(|re| !func.is_some_and(|f| re.is_match(f))) {
+            return false;
+        }
+        return !f.exclude_func.as_ref().is_some_and(|re| func.is_some_and(|f| re.is_match(f)));
+    });
+
+    let site_json = |(addr, nr): &(u64, Option<u64>)| json!({
+        "address": addr,
+        "symbol": symbols.symbolize(*addr),
+        "syscall": nr.map(|nr| json!({"number": nr, "name": syscalls::name(nr, arch)})),
+    });
 
-    let mut gadgets_hs = HashSet::new();
+    let count = |kind| gadgets.iter().filter(|g| g.kind() == kind).count();
+    let (rop, jop, call) = (count(RootKind::Return), count(RootKind::Jump), count(RootKind::Call));
+    let syscall = count(RootKind::Syscall);
+    let summary = json!({"summary": {
+        "unique_gadgets": gadgets.len(),
+        "rop": rop,
+        "jop": jop,
+        "call": call,
+        "syscall": syscall,
+        "sites": sites.len(),
+    }});
 
-    for root in gadget_roots {
-        let gadgets = core::find_gadgets_at_root(&cs, root, addr, &code, args.max);
-        for gadget in gadgets {
-            if gadget.satisfies(&query) {
-                gadgets_hs.insert(gadget);
+    match outmode {
+        OutputMode::Json => {
+            let gadgets: Vec<_> = gadgets.iter().map(|g| g.to_json(query)).collect();
+            let sites: Vec<_> = sites.iter().map(site_json).collect();
+            println!("{:#}", json!({"gadgets": gadgets, "sites": sites, "summary": summary["summary"]}));
+            return;
+        }
+        OutputMode::Python => {
+            println!("# Gadgets of '{}', generated by rv", path);
+            println!("BASE = {:#x}", base);
+            println!("gadgets = {{}}");
+            // Equivalent gadgets may print the same, the first address is kept
+            let mut names = HashSet::new();
+            for gadget in gadgets.iter().filter(|g| names.insert(g.text())) {
+                gadget.print(query, outmode);
+            }
+            for (addr, nr) in &sites {
+                let name = nr.and_then(|nr| syscalls::name(nr, arch)).unwrap_or("unknown");
+                println!("gadgets['ecall@{:#x} {}'] = {:#x}", addr, name, addr);
             }
+            println!();
+            println!("def rebase(base):");
+            println!("    \"\"\"Gadget addresses for the image loaded at `base`\"\"\"");
+            println!("    return {{name: addr - BASE + base for name, addr in gadgets.items()}}");
+            return;
         }
+        OutputMode::JsonLines => {
+            for gadget in &gadgets {
+                gadget.print(query, outmode);
+            }
+            for site in &sites {
+                println!("{}", json!({"site": site_json(site)}));
+            }
+            println!("{}", summary);
+            return;
+        }
+        _ => {}
     }
 
-    for gadget in &gadgets_hs {
-        gadget.print(&query, outmode);
+    for gadget in &gadgets {
+        gadget.print(query, outmode);
         if let OutputMode::Block = outmode {
             println!();
         }
     }
 
+    if f.syscall {
+        println!("{}", "Syscall sites:".bold());
+        for (addr, nr) in &sites {
+            let addr_str = format!("{:#0w$x}", addr, w = arch.addr_width() + 2);
+            let sym = symbols.symbolize(*addr).map(|s| format!(" <{}>", s)).unwrap_or_default();
+            let a7 = match nr {
+                Some(nr) => format!("a7 = {} ({})", nr, syscalls::name(*nr, arch).unwrap_or("unknown")),
+                None => String::from("a7 unknown"),
+            };
+            println!("  {}{}   {}", addr_str.yellow(), sym.green(), a7);
+        }
+    }
+
     println!("----------");
-    println!("Found {} unique gadgets.", gadgets_hs.len());
+    if f.syscall {
+        println!("Found {} unique gadgets ({} rop, {} jop, {} call, {} syscall) and {} ecall sites.", gadgets.len(), rop, jop, call, syscall, sites.len());
+    } else if syscall > 0 {
+        println!("Found {} unique gadgets ({} rop, {} jop, {} call, {} syscall).", gadgets.len(), rop, jop, call, syscall);
+    } else {
+        println!("Found {} unique gadgets ({} rop, {} jop, {} call).", gadgets.len(), rop, jop, call);
+    }
 }
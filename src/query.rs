@@ -1,67 +1,162 @@
 use std::{iter, fmt};
 
 use capstone::prelude::*;
-use capstone::arch::riscv::RiscVOperand;
 
-use crate::gadget::{Gadget, GadgetInsn};
+use crate::core;
+use crate::expr::QueryExpr;
+use crate::gadget::{Gadget, GadgetClass, GadgetInsn, Operand};
 
-#[derive (Debug)]
+/// Values of a filter flag. An instruction matches `any` when it matches one
+/// of its values, a gadget matches `all` when each value is matched by one of
+/// its instructions
+#[derive (Clone, Debug)]
+pub struct Filter<T> {
+    pub any: Vec<T>,
+    pub all: Vec<T>,
+}
+
+impl<T> Default for Filter<T> {
+    fn default() -> Self {
+        return Filter {any: Vec::new(), all: Vec::new()};
+    }
+}
+
+impl<T> Filter<T> {
+
+    pub fn new(any: Vec<T>, all: Vec<T>) -> Self {
+        return Filter {any, all};
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.any.is_empty() && self.all.is_empty();
+    }
+
+    fn accepts_any(&self, f: impl Fn(&T) -> bool) -> bool {
+        return self.any.is_empty() || self.any.iter().any(f);
+    }
+
+    fn describe(&self, name: impl Fn(&T) -> String) -> String {
+        let join = |vals: &[T]| vals.iter().map(&name).collect::<Vec<_>>().join(", ");
+        return match (self.any.is_empty(), self.all.is_empty()) {
+            (true, true) => String::from("-"),
+            (false, true) => format!("any of {}", join(&self.any)),
+            (true, false) => format!("all of {}", join(&self.all)),
+            (false, false) => format!("any of {} and all of {}", join(&self.any), join(&self.all)),
+        };
+    }
+
+}
+
+#[derive (Clone, Debug, Default)]
 pub struct Query {
-    pub rr: Option<capstone::RegId>,
-    pub wr: Option<capstone::RegId>,
-    pub imm: Option<i64>,
-    pub op: Option<capstone::InsnId>,
+    pub rr: Filter<RegId>,
+    pub wr: Filter<RegId>,
+    pub imm: Filter<i64>,
+    pub op: Filter<InsnId>,
     pub ds: bool,
-    empty: bool,
+    pub sp_delta: Option<i64>,
+    pub expr: Option<QueryExpr>,
+    /// Registers the gadget must leave untouched, root link register included
+    pub no_write: Vec<RegId>,
+    pub no_op: Vec<InsnId>,
+    pub no_mem_write: bool,
+    pub no_branch_inside: bool,
+    /// Only gadgets of one of these classes
+    pub classes: Vec<GadgetClass>,
+}
+
+fn reads(ins: &GadgetInsn, reg: &RegId) -> bool {
+    return ins.regs_read().contains(reg);
+}
+
+fn writes(ins: &GadgetInsn, reg: &RegId) -> bool {
+    return ins.regs_written().contains(reg);
+}
+
+fn uses_imm(ins: &GadgetInsn, val: &i64) -> bool {
+    return ins.operands().contains(&Operand::Imm(*val));
+}
+
+fn is_op(ins: &GadgetInsn, op: &InsnId) -> bool {
+    return ins.id() == *op;
 }
 
 impl Query {
 
-    pub fn create_from(rr: Option<RegId>, wr: Option<RegId>, imm: Option<i64>, op: Option<InsnId>, ds: bool) -> Self {
-        let empty: bool = rr == None && wr == None && op == None && imm == None;
-        return Query {rr, wr, imm, op, ds, empty};
+    pub fn create_from(rr: Filter<RegId>, wr: Filter<RegId>, imm: Filter<i64>, op: Filter<InsnId>, ds: bool) -> Self {
+        return Query {rr, wr, imm, op, ds, ..Default::default()};
     }
 
-    pub fn is_satisfied_by_ins(&self, ins: &GadgetInsn) -> bool {
-        if self.empty {
-            return false;
-        }
-        if let Some(op) = self.op {
-            if op != ins.id() {
-                return false;
-            }
-        }
-        if let Some(wr) = self.wr {
-            if !ins.operands().contains(&RiscVOperand::Reg(wr)) {
-                return false;
-            }
-        }
-        if let Some(rr) = self.rr {
-            if !ins.operands().contains(&RiscVOperand::Reg(rr)) {
-                return false;
-            }
+    fn has_any(&self) -> bool {
+        return !(self.rr.any.is_empty() && self.wr.any.is_empty() && self.imm.any.is_empty() && self.op.any.is_empty());
+    }
+
+    fn has_all(&self) -> bool {
+        return !(self.rr.all.is_empty() && self.wr.all.is_empty() && self.imm.all.is_empty() && self.op.all.is_empty());
+    }
+
+    /// Whether a single instruction matches every `any` filter
+    fn matches_any(&self, ins: &GadgetInsn) -> bool {
+        return self.op.accepts_any(|op| is_op(ins, op))
+            && self.wr.accepts_any(|reg| writes(ins, reg))
+            && self.rr.accepts_any(|reg| reads(ins, reg))
+            && self.imm.accepts_any(|val| uses_imm(ins, val));
+    }
+
+    /// Whether the instruction matches one of the values of an `all` filter
+    fn matches_one_of_all(&self, ins: &GadgetInsn) -> bool {
+        return self.op.all.iter().any(|op| is_op(ins, op))
+            || self.wr.all.iter().any(|reg| writes(ins, reg))
+            || self.rr.all.iter().any(|reg| reads(ins, reg))
+            || self.imm.all.iter().any(|val| uses_imm(ins, val));
+    }
+
+    /// Whether the gadget hits one of the exclusion filters
+    fn clobbers(&self, gadget: &Gadget) -> bool {
+        if !self.no_write.is_empty() && iter::once(gadget.root()).chain(gadget.insns()).any(|ins| self.no_write.iter().any(|reg| writes(ins, reg))) {
+            return true;
         }
-        if let Some(val) = self.imm {
-            if !ins.operands().contains(&RiscVOperand::Imm(val)) {
-                return false;
-            }
+        return gadget.insns().iter().any(|ins| {
+            self.no_op.iter().any(|op| is_op(ins, op))
+                || (self.no_mem_write && ins.writes_memory())
+                || (self.no_branch_inside && core::is_cond_branch(ins.id()))
+        });
+    }
+
+    pub fn is_satisfied_by_ins(&self, ins: &GadgetInsn) -> bool {
+        if self.expr.as_ref().is_some_and(|e| e.highlights(ins)) {
+            return true;
         }
-        return true;
+        return (self.has_any() && self.matches_any(ins)) || self.matches_one_of_all(ins);
     }
 
     pub fn is_satisfied_by_gadget(&self, gadget: &Gadget) -> bool {
         if self.ds && !gadget.is_dispatcher() {
             return false
         }
-        if self.empty {
-            return true;
+        if self.sp_delta.is_some() && gadget.stack_effect().sp_delta != self.sp_delta {
+            return false
+        }
+        if self.expr.as_ref().is_some_and(|e| !e.is_satisfied_by_gadget(gadget)) {
+            return false
+        }
+        if self.clobbers(gadget) {
+            return false
+        }
+        if !self.classes.is_empty() && !gadget.classes().iter().any(|c| self.classes.contains(c)) {
+            return false
+        }
+        if self.has_any() && !gadget.insns().iter().any(|ins| self.matches_any(ins)) {
+            return false;
         }
-        for ins in gadget.insns() {
-            if self.is_satisfied_by_ins(&ins) {
-                return true;
-            }
+        if self.has_all() {
+            let insns = gadget.insns();
+            return self.op.all.iter().all(|op| insns.iter().any(|ins| is_op(ins, op)))
+                && self.wr.all.iter().all(|reg| insns.iter().any(|ins| writes(ins, reg)))
+                && self.rr.all.iter().all(|reg| insns.iter().any(|ins| reads(ins, reg)))
+                && self.imm.all.iter().all(|val| insns.iter().any(|ins| uses_imm(ins, val)));
         }
-        return false;
+        return true;
     }
 
 }
@@ -76,9 +171,21 @@ impl fmt::Display for Query {
             .build()
             .expect("Failed to create Capstone object");
 
-        writeln!(f, "instruction:    {}", cs.insn_name(self.op.unwrap_or(InsnId(0))).unwrap_or(String::from("-")))?;
-        writeln!(f, "read register:  {}", cs.reg_name(self.rr.unwrap_or(RegId(0))).unwrap_or(String::from("-")))?;
-        writeln!(f, "write register  {}", cs.reg_name(self.wr.unwrap_or(RegId(0))).unwrap_or(String::from("-")))?;
+        writeln!(f, "instruction:    {}", self.op.describe(|op| cs.insn_name(*op).unwrap_or(String::from("?"))))?;
+        writeln!(f, "read register:  {}", self.rr.describe(|reg| core::reg_name(*reg)))?;
+        writeln!(f, "write register: {}", self.wr.describe(|reg| core::reg_name(*reg)))?;
+        writeln!(f, "immediate:      {}", self.imm.describe(|val| format!("{:#x}", val)))?;
+        if !self.no_write.is_empty() || !self.no_op.is_empty() {
+            let regs = self.no_write.iter().map(|reg| core::reg_name(*reg));
+            let ops = self.no_op.iter().map(|op| cs.insn_name(*op).unwrap_or(String::from("?")));
+            writeln!(f, "excluded:       {}", regs.chain(ops).collect::<Vec<_>>().join(", "))?;
+        }
+        if !self.classes.is_empty() {
+            writeln!(f, "classes:        {}", self.classes.iter().map(|c| c.name()).collect::<Vec<_>>().join(", "))?;
+        }
+        if let Some(expr) = &self.expr {
+            writeln!(f, "expression:     {}", expr)?;
+        }
         Ok(())
     }
 }
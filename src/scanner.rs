@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::{thread, vec};
+
+use capstone::prelude::RegId;
+
+use crate::cache::InsnCache;
+use crate::core::{self, Arch};
+use crate::gadget::{Gadget, GadgetRoot, KindFilter};
+use crate::query::Query;
+
+/// Address of an `ecall`, with the system call number set before it when constant
+pub type SyscallSite = (u64, Option<u64>);
+
+/// Filters of a gadget search, shared by the [`Scanner`] and the gadget index
+#[derive(Clone, Debug)]
+pub struct Selection {
+    /// Registers the roots jump to, any register when empty
+    pub jr: Vec<RegId>,
+    pub kinds: KindFilter,
+    pub query: Query,
+    /// Bytes the gadget addresses must not contain
+    pub bad: Vec<u8>,
+    /// Trims the gadgets to their first entry point avoiding `bad` instead of dropping them
+    pub later: bool,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        return Selection {jr: Vec::new(), kinds: KindFilter::All, query: Query::default(), bad: Vec::new(), later: false};
+    }
+}
+
+impl Selection {
+
+    /// Roots of the code with the selected kinds and jump registers
+    pub fn roots(&self, cache: &InsnCache) -> Vec<GadgetRoot> {
+        return core::find_gadget_roots(cache, &self.jr, self.kinds);
+    }
+
+    /// Gadgets with a selected root, avoiding the bad bytes and satisfying the query
+    pub fn select(&self, gadgets: Vec<Gadget>, arch: Arch) -> Vec<Gadget> {
+        return gadgets.into_iter()
+            .filter(|g| core::is_selected_root(g.root(), g.kind(), &self.jr, self.kinds))
+            .filter_map(|g| g.avoiding(&self.bad, arch, self.later))
+            .filter(|g| g.satisfies(&self.query))
+            .collect();
+    }
+
+}
+
+/// Gadget search over a single block of code
+///
+/// ```no_run
+/// use raccoonv::{Scanner, core::Arch};
+///
+/// let code = std::fs::read("firmware.bin").unwrap();
+/// for gadget in Scanner::new(&code).base(0x8000_0000).arch(Arch::RV64).scan() {
+///     println!("{:#x}", gadget.address());
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Scanner<'a> {
+    code: &'a [u8],
+    base: u64,
+    arch: Arch,
+    max: usize,
+    selection: Selection,
+    threads: usize,
+}
+
+impl<'a> Scanner<'a> {
+
+    pub fn new(code: &'a [u8]) -> Self {
+        return Scanner {
+            code,
+            base: 0,
+            arch: Arch::RV32,
+            max: 5,
+            selection: Selection::default(),
+            threads: 1,
+        };
+    }
+
+    /// Address at which the first byte of the code is mapped
+    pub fn base(mut self, addr: u64) -> Self {
+        self.base = addr;
+        self
+    }
+
+    pub fn arch(mut self, arch: Arch) -> Self {
+        self.arch = arch;
+        self
+    }
+
+    /// Maximum number of instructions before the gadget root
+    pub fn max_depth(mut self, max: usize) -> Self {
+        self.max = max;
+        self
+    }
+
+    /// Only keep gadgets ending with a jump to one of `regs`, any register when empty
+    pub fn jump_regs(mut self, regs: Vec<RegId>) -> Self {
+        self.selection.jr = regs;
+        self
+    }
+
+    /// Only keep gadgets whose root is of the selected kinds
+    pub fn kinds(mut self, kinds: KindFilter) -> Self {
+        self.selection.kinds = kinds;
+        self
+    }
+
+    pub fn query(mut self, query: Query) -> Self {
+        self.selection.query = query;
+        self
+    }
+
+    /// Only keep gadgets whose address contains none of the `bad` bytes. With
+    /// `later`, gadgets are trimmed to their first entry point avoiding them.
+    pub fn bad_bytes(mut self, bad: Vec<u8>, later: bool) -> Self {
+        self.selection.bad = bad;
+        self.selection.later = later;
+        self
+    }
+
+    /// Replaces the jump registers, kinds, query and bad bytes filters at once
+    pub fn selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Number of threads used for the search. With a single thread, gadgets are
+    /// searched lazily while iterating
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    fn cache(&self) -> InsnCache {
+        if self.threads > 1 {
+            return InsnCache::build_parallel(self.arch, self.code, self.base, self.threads);
+        }
+        return InsnCache::build(self.arch, self.code, self.base);
+    }
+
+    /// Returns the unique gadgets satisfying the query, ordered by root address
+    pub fn scan(&self) -> Gadgets {
+        let cache = self.cache();
+        let roots = self.selection.roots(&cache);
+        let (roots, pending) = if self.threads > 1 {
+            (Vec::new(), self.search_parallel(&cache, roots))
+        } else {
+            (roots, Vec::new())
+        };
+        return Gadgets {
+            cache,
+            max: self.max,
+            arch: self.arch,
+            selection: self.selection.clone(),
+            roots: roots.into_iter(),
+            pending: pending.into_iter(),
+            seen: HashSet::new(),
+        };
+    }
+
+    /// Every gadget found at the selected roots, before the bad bytes, the query
+    /// and the deduplication apply. The gadgets of a root stay together, in the
+    /// order the search finds them.
+    pub fn all_gadgets(&self) -> Vec<Gadget> {
+        return self.gadgets_in(&self.cache());
+    }
+
+    /// Addresses of every `ecall` of the code, with the system call number the
+    /// instructions before it set when it is constant
+    pub fn syscall_sites(&self) -> Vec<SyscallSite> {
+        return self.syscalls_in(&self.cache()).1;
+    }
+
+    /// What a gadget index keeps, decoding the code once: the gadgets of
+    /// [`Scanner::all_gadgets`], those ending with an `ecall`, and the
+    /// [`Scanner::syscall_sites`]
+    pub fn index_gadgets(&self) -> (Vec<Gadget>, Vec<Gadget>, Vec<SyscallSite>) {
+        let cache = self.cache();
+        let (syscalls, sites) = self.syscalls_in(&cache);
+        return (self.gadgets_in(&cache), syscalls, sites);
+    }
+
+    fn gadgets_in(&self, cache: &InsnCache) -> Vec<Gadget> {
+        return self.selection.roots(cache).into_iter()
+            .flat_map(|root| core::find_gadgets_at_root(cache, root, self.max))
+            .collect();
+    }
+
+    /// Gadgets of every `ecall` root, and the sites with their system call number
+    fn syscalls_in(&self, cache: &InsnCache) -> (Vec<Gadget>, Vec<SyscallSite>) {
+        let mut gadgets = Vec::new();
+        let mut sites = Vec::new();
+        for root in core::find_gadget_roots(cache, &[], KindFilter::Syscall) {
+            let addr = root.root.address();
+            let found = core::find_gadgets_at_root(cache, root, self.max);
+            sites.push((addr, found.iter().find_map(|g| g.syscall())));
+            gadgets.extend(found);
+        }
+        return (gadgets, sites);
+    }
+
+    /// Splits the roots in contiguous chunks handled by one thread each. Results
+    /// are joined in chunk order to keep the output stable.
+    fn search_parallel(&self, cache: &InsnCache, roots: Vec<GadgetRoot>) -> Vec<Gadget> {
+        if roots.is_empty() {
+            return Vec::new();
+        }
+        let (max, selection, arch) = (self.max, &self.selection, self.arch);
+        let chunk = roots.len().div_ceil(self.threads);
+        return thread::scope(|s| {
+            let handles: Vec<_> = roots.chunks(chunk).map(|roots| {
+                s.spawn(move || {
+                    roots.iter()
+                        .flat_map(|root| selection.select(core::find_gadgets_at_root(cache, root.clone(), max), arch))
+                        .collect::<Vec<Gadget>>()
+                })
+            }).collect();
+            handles.into_iter().flat_map(|h| h.join().expect("Gadget search thread panicked")).collect()
+        });
+    }
+
+}
+
+pub struct Gadgets {
+    cache: InsnCache,
+    max: usize,
+    arch: Arch,
+    selection: Selection,
+    roots: vec::IntoIter<GadgetRoot>,
+    pending: vec::IntoIter<Gadget>,
+    seen: HashSet<Gadget>,
+}
+
+impl Iterator for Gadgets {
+    type Item = Gadget;
+
+    fn next(&mut self) -> Option<Gadget> {
+        loop {
+            // Pending gadgets are already filtered, by the workers or below
+            if let Some(gadget) = self.pending.next() {
+                if self.seen.insert(gadget.clone()) {
+                    return Some(gadget);
+                }
+            } else if let Some(root) = self.roots.next() {
+                let gadgets = core::find_gadgets_at_root(&self.cache, root, self.max);
+                self.pending = self.selection.select(gadgets, self.arch).into_iter();
+            } else {
+                return None;
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use elf::{ElfBytes, endian};
+use serde_json::{json, Value};
+
+use crate::err::RVError;
+
+/// Function symbols of an ELF file, used to locate gadgets
+#[derive (Clone, Debug, Default)]
+pub struct Symbols {
+    /// `(address, size, name)` sorted by address
+    funcs: Vec<(u64, u64, String)>,
+}
+
+impl Symbols {
+
+    /// Collects the functions of `.symtab` and `.dynsym`
+    pub fn from_elf(data: &[u8]) -> Result<Self, RVError> {
+        let elf = ElfBytes::<endian::AnyEndian>::minimal_parse(data)
+            .map_err(|_| RVError {msg: String::from("Make sure to provide a valid ELF file")})?;
+        let mut funcs = Vec::new();
+
+        for table in [elf.symbol_table(), elf.dynamic_symbol_table()] {
+            let Ok(Some((syms, strs))) = table else {
+                continue;
+            };
+            for sym in syms.iter() {
+                let is_func = matches!(sym.st_symtype(), elf::abi::STT_FUNC | elf::abi::STT_GNU_IFUNC);
+                if !is_func || sym.is_undefined() {
+                    continue;
+                }
+                if let Ok(name) = strs.get(sym.st_name as usize) {
+                    if !name.is_empty() {
+                        funcs.push((sym.st_value, sym.st_size, String::from(name)));
+                    }
+                }
+            }
+        }
+        funcs.sort();
+        funcs.dedup_by(|a, b| a.0 == b.0 && a.2 == b.2);
+        return Ok(Symbols {funcs});
+    }
+
+    pub fn to_json(&self) -> Value {
+        return json!(self.funcs);
+    }
+
+    pub fn from_json(funcs: &Value) -> Result<Self, RVError> {
+        let bad = || RVError {msg: String::from("corrupted symbol records")};
+        let funcs = funcs.as_array().ok_or_else(bad)?.iter().map(|func| {
+            let addr = func.get(0).and_then(Value::as_u64).ok_or_else(bad)?;
+            let size = func.get(1).and_then(Value::as_u64).ok_or_else(bad)?;
+            let name = func.get(2).and_then(Value::as_str).ok_or_else(bad)?;
+            return Ok((addr, size, String::from(name)));
+        }).collect::<Result<Vec<_>, RVError>>()?;
+        return Ok(Symbols {funcs});
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.funcs.is_empty();
+    }
+
+    /// Moves the symbols of an image loaded at `from` so that it is loaded at `to`
+    pub fn rebase(&mut self, from: u64, to: u64) {
+        for (addr, _, _) in &mut self.funcs {
+            *addr = addr.wrapping_sub(from).wrapping_add(to);
+        }
+    }
+
+    /// Function containing `addr` and the offset of `addr` in it. Functions
+    /// without a size extend up to the next symbol.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let idx = self.funcs.partition_point(|(start, _, _)| *start <= addr).checked_sub(1)?;
+        let (start, size, name) = &self.funcs[idx];
+        if *size != 0 && addr - start >= *size {
+            return None;
+        }
+        return Some((name, addr - start));
+    }
+
+    /// `func+0xoff` representation of `addr`
+    pub fn symbolize(&self, addr: u64) -> Option<String> {
+        return self.lookup(addr).map(|(name, off)| format!("{}+{:#x}", name, off));
+    }
+
+}
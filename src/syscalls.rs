@@ -0,0 +1,153 @@
+use crate::core::Arch;
+
+/// Names of the Linux system calls of RISC-V, which uses the generic table
+/// (`include/uapi/asm-generic/unistd.h`), indexed by number up to 294
+const NAMES: &[&str] = &[
+    "io_setup", "io_destroy", "io_submit", "io_cancel", "io_getevents",
+    "setxattr", "lsetxattr", "fsetxattr", "getxattr", "lgetxattr",
+    "fgetxattr", "listxattr", "llistxattr", "flistxattr", "removexattr",
+    "lremovexattr", "fremovexattr", "getcwd", "lookup_dcookie", "eventfd2",
+    "epoll_create1", "epoll_ctl", "epoll_pwait", "dup", "dup3",
+    "fcntl", "inotify_init1", "inotify_add_watch", "inotify_rm_watch", "ioctl",
+    "ioprio_set", "ioprio_get", "flock", "mknodat", "mkdirat",
+    "unlinkat", "symlinkat", "linkat", "renameat", "umount2",
+    "mount", "pivot_root", "nfsservctl", "statfs", "fstatfs",
+    "truncate", "ftruncate", "fallocate", "faccessat", "chdir",
+    "fchdir", "chroot", "fchmod", "fchmodat", "fchownat",
+    "fchown", "openat", "close", "vhangup", "pipe2",
+    "quotactl", "getdents64", "lseek", "read", "write",
+    "readv", "writev", "pread64", "pwrite64", "preadv",
+    "pwritev", "sendfile", "pselect6", "ppoll", "signalfd4",
+    "vmsplice", "splice", "tee", "readlinkat", "newfstatat",
+    "fstat", "sync", "fsync", "fdatasync", "sync_file_range",
+    "timerfd_create", "timerfd_settime", "timerfd_gettime", "utimensat", "acct",
+    "capget", "capset", "personality", "exit", "exit_group",
+    "waitid", "set_tid_address", "unshare", "futex", "set_robust_list",
+    "get_robust_list", "nanosleep", "getitimer", "setitimer", "kexec_load",
+    "init_module", "delete_module", "timer_create", "timer_gettime", "timer_getoverrun",
+    "timer_settime", "timer_delete", "clock_settime", "clock_gettime", "clock_getres",
+    "clock_nanosleep", "syslog", "ptrace", "sched_setparam", "sched_setscheduler",
+    "sched_getscheduler", "sched_getparam", "sched_setaffinity", "sched_getaffinity", "sched_yield",
+    "sched_get_priority_max", "sched_get_priority_min", "sched_rr_get_interval", "restart_syscall", "kill",
+    "tkill", "tgkill", "sigaltstack", "rt_sigsuspend", "rt_sigaction",
+    "rt_sigprocmask", "rt_sigpending", "rt_sigtimedwait", "rt_sigqueueinfo", "rt_sigreturn",
+    "setpriority", "getpriority", "reboot", "setregid", "setgid",
+    "setreuid", "setuid", "setresuid", "getresuid", "setresgid",
+    "getresgid", "setfsuid", "setfsgid", "times", "setpgid",
+    "getpgid", "getsid", "setsid", "getgroups", "setgroups",
+    "uname", "sethostname", "setdomainname", "getrlimit", "setrlimit",
+    "getrusage", "umask", "prctl", "getcpu", "gettimeofday",
+    "settimeofday", "adjtimex", "getpid", "getppid", "getuid",
+    "geteuid", "getgid", "getegid", "gettid", "sysinfo",
+    "mq_open", "mq_unlink", "mq_timedsend", "mq_timedreceive", "mq_notify",
+    "mq_getsetattr", "msgget", "msgctl", "msgrcv", "msgsnd",
+    "semget", "semctl", "semtimedop", "semop", "shmget",
+    "shmctl", "shmat", "shmdt", "socket", "socketpair",
+    "bind", "listen", "accept", "connect", "getsockname",
+    "getpeername", "sendto", "recvfrom", "setsockopt", "getsockopt",
+    "shutdown", "sendmsg", "recvmsg", "readahead", "brk",
+    "munmap", "mremap", "add_key", "request_key", "keyctl",
+    "clone", "execve", "mmap", "fadvise64", "swapon",
+    "swapoff", "mprotect", "msync", "mlock", "munlock",
+    "mlockall", "munlockall", "mincore", "madvise", "remap_file_pages",
+    "mbind", "get_mempolicy", "set_mempolicy", "migrate_pages", "move_pages",
+    "rt_tgsigqueueinfo", "perf_event_open", "accept4", "recvmmsg", "",
+    "", "", "", "", "",
+    "", "", "", "", "",
+    "", "", "", "", "riscv_flush_icache",
+    "wait4", "prlimit64", "fanotify_init", "fanotify_mark", "name_to_handle_at",
+    "open_by_handle_at", "clock_adjtime", "syncfs", "setns", "sendmmsg",
+    "process_vm_readv", "process_vm_writev", "kcmp", "finit_module", "sched_setattr",
+    "sched_getattr", "renameat2", "seccomp", "getrandom", "memfd_create",
+    "bpf", "execveat", "userfaultfd", "membarrier", "mlock2",
+    "copy_file_range", "preadv2", "pwritev2", "pkey_mprotect", "pkey_alloc",
+    "pkey_free", "statx", "io_pgetevents", "rseq", "kexec_file_load",
+];
+
+/// Calls of the generic table that RV32 Linux leaves out: the 32-bit time
+/// ones, replaced by 403 to 423, and the old stat and rlimit ones.
+const RV64_ONLY: &[u64] = &[
+    4, 72, 73, 79, 80, 86, 87, 88, 98, 101, 108, 110, 112, 113, 114, 115,
+    127, 137, 163, 164, 169, 170, 171, 182, 183, 192, 243, 266, 292,
+];
+
+/// Name of the Linux system call `nr` on RISC-V. The 64-bit time variants
+/// only exist on RV32, the calls they replace only on RV64.
+pub fn name(nr: u64, arch: Arch) -> Option<&'static str> {
+    let missing = match arch {
+        Arch::RV32 => RV64_ONLY.contains(&nr),
+        Arch::RV64 => (403..=423).contains(&nr),
+    };
+    if missing {
+        return None;
+    }
+    if let Some(name) = NAMES.get(nr as usize).filter(|name| !name.is_empty()) {
+        return Some(name);
+    }
+    let name = match nr {
+        403 => "clock_gettime64",
+        404 => "clock_settime64",
+        405 => "clock_adjtime64",
+        406 => "clock_getres_time64",
+        407 => "clock_nanosleep_time64",
+        408 => "timer_gettime64",
+        409 => "timer_settime64",
+        410 => "timerfd_gettime64",
+        411 => "timerfd_settime64",
+        412 => "utimensat_time64",
+        413 => "pselect6_time64",
+        414 => "ppoll_time64",
+        416 => "io_pgetevents_time64",
+        417 => "recvmmsg_time64",
+        418 => "mq_timedsend_time64",
+        419 => "mq_timedreceive_time64",
+        420 => "semtimedop_time64",
+        421 => "rt_sigtimedwait_time64",
+        422 => "futex_time64",
+        423 => "sched_rr_get_interval_time64",
+        424 => "pidfd_send_signal",
+        425 => "io_uring_setup",
+        426 => "io_uring_enter",
+        427 => "io_uring_register",
+        428 => "open_tree",
+        429 => "move_mount",
+        430 => "fsopen",
+        431 => "fsconfig",
+        432 => "fsmount",
+        433 => "fspick",
+        434 => "pidfd_open",
+        435 => "clone3",
+        436 => "close_range",
+        437 => "openat2",
+        438 => "pidfd_getfd",
+        439 => "faccessat2",
+        440 => "process_madvise",
+        441 => "epoll_pwait2",
+        442 => "mount_setattr",
+        443 => "quotactl_fd",
+        444 => "landlock_create_ruleset",
+        445 => "landlock_add_rule",
+        446 => "landlock_restrict_self",
+        447 => "memfd_secret",
+        448 => "process_mrelease",
+        449 => "futex_waitv",
+        450 => "set_mempolicy_home_node",
+        _ => return None,
+    };
+    return Some(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_syscalls_follow_the_arch() {
+        assert_eq!(name(113, Arch::RV64), Some("clock_gettime"));
+        assert_eq!(name(113, Arch::RV32), None);
+        assert_eq!(name(403, Arch::RV32), Some("clock_gettime64"));
+        assert_eq!(name(403, Arch::RV64), None);
+        assert_eq!(name(221, Arch::RV32), Some("execve"));
+        assert_eq!(name(221, Arch::RV64), Some("execve"));
+    }
+}